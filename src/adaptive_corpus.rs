@@ -11,27 +11,129 @@
 //! ```no_run
 //! use std::fs;
 //! use kc::Corpus;
-//! use km_adaptive_corpus::AdaptiveCorpus;
+//! use km_adaptive_corpus::{AdaptiveCorpus, Rule};
 //!
 //! let b = fs::read("./corpora/shai-iweb.corpus").unwrap();
 //! let mut corpus: Corpus = rmp_serde::from_slice(&b).unwrap();
-//! <Corpus as AdaptiveCorpus<[char; 1]>>::adapt_ngrams(&mut corpus, ['h', 'e'], ['h', '†']);
-//! <Corpus as AdaptiveCorpus<[char; 2]>>::adapt_ngrams(&mut corpus, ['h', 'e'], ['h', '†']);
-//! <Corpus as AdaptiveCorpus<[char; 3]>>::adapt_ngrams(&mut corpus, ['h', 'e'], ['h', '†']);
+//! let rule = Rule::new(['h', 'e'], ['h', '†']);
+//! <Corpus as AdaptiveCorpus<[char; 1]>>::adapt_ngrams(&mut corpus, rule);
+//! <Corpus as AdaptiveCorpus<[char; 2]>>::adapt_ngrams(&mut corpus, rule);
+//! <Corpus as AdaptiveCorpus<[char; 3]>>::adapt_ngrams(&mut corpus, rule);
 //! ```
 
 pub mod bigrams;
+pub mod chain;
+pub mod context;
+pub mod dry_run;
+pub mod explain;
+pub mod magic_key;
+pub mod marginal;
 pub mod monograms;
 pub mod pentagrams;
+pub mod progress;
 pub mod quadgrams;
 pub mod trigrams;
 
-use crate::CorpusExt;
+use crate::{CorpusExt, Table};
+
+use context::{Context, match_fraction};
+use dry_run::{DeltaSet, Deltas};
+use explain::{Pass, Tracer};
+use marginal::Strategy;
+use progress::CancelToken;
 
 #[cfg(test)]
-mod tests;
+pub(crate) mod tests;
+
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use std::fmt::{self, Debug};
+
+use tracing::warn;
+
+/// A bigram substitution, eg. `he -> h†`.
+///
+/// [`Rule::rate`] is the fraction of the text typed using the rule, eg. `0.7`
+/// if the magic key is only used 70% of the time. Each transfer is scaled by
+/// it and rounded once, and the same amount is taken from one ngram and given
+/// to another, so every table keeps its total. A count is within half a count
+/// per transfer into or out of it of the way from the original to the fully
+/// adapted corpus.
+///
+/// `before` and `after` restrict the rule to occurrences of `old` with
+/// matching neighbours, eg. `he -> h†` except when followed by a space.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Rule {
+    pub old: [char; 2],
+    pub new: [char; 2],
+    #[serde(deserialize_with = "deserialize_rate")]
+    rate: f64,
+    pub before: Context,
+    pub after: Context,
+}
+
+impl Rule {
+    pub const fn new(old: [char; 2], new: [char; 2]) -> Self {
+        Self {
+            old,
+            new,
+            rate: 1.0,
+            before: Context::Any,
+            after: Context::Any,
+        }
+    }
+
+    /// Panics unless `rate` is in `[0, 1]`.
+    pub fn with_rate(self, rate: f64) -> Self {
+        assert!((0.0..=1.0).contains(&rate), "Rate out of range: {rate}");
+        Self { rate, ..self }
+    }
+
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    pub fn with_before(self, before: Context) -> Self {
+        Self { before, ..self }
+    }
+
+    pub fn with_after(self, after: Context) -> Self {
+        Self { after, ..self }
+    }
+
+    fn has_context(&self) -> bool {
+        self.before != Context::Any || self.after != Context::Any
+    }
+
+    /// The part of `count` that's moved by this rule, given that only
+    /// `fraction` of the occurrences have a matching context, at its rate.
+    fn scale(&self, count: u64, fraction: f64) -> u64 {
+        let share = fraction * self.rate;
+        if share == 1.0 {
+            count
+        } else {
+            (count as f64 * share).round() as u64
+        }
+    }
+}
+
+/// Refuses rates that [`Rule::with_rate`] would.
+fn deserialize_rate<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    let rate = f64::deserialize(deserializer)?;
+    if (0.0..=1.0).contains(&rate) {
+        Ok(rate)
+    } else {
+        Err(D::Error::custom(format!("Rate out of range: {rate}")))
+    }
+}
 
-use std::fmt::Debug;
+/// Splits `sum` into the part taken from the substituted ngram and the part
+/// left on the original, when only `fraction` of it was substituted.
+fn split_boundary_sum(sum: u64, fraction: f64) -> (u64, u64) {
+    let moved = (sum as f64 * fraction).round() as u64;
+    (moved, sum - moved)
+}
 
 // # Generics
 
@@ -42,31 +144,79 @@ use std::fmt::Debug;
 /// - `N`: New, the length of the replacement ngram.
 #[derive(Debug)]
 pub struct ExpansionStruct<O, N> {
+    kind: ExpansionKind,
     old: O,
     new: N,
-    count: Option<u32>,
+    count: Option<u64>,
+    moved: Option<u64>,
 }
 
 impl<O, N> ExpansionStruct<O, N> {
-    fn new(old: O, new: N) -> Self {
+    fn new(kind: ExpansionKind, old: O, new: N) -> Self {
         Self {
+            kind,
             old,
             new,
             count: None,
+            moved: None,
         }
     }
 
-    fn read_count(&self) -> u32 {
+    /// Occurrences of `old`, before the rule's context.
+    fn read_count(&self) -> u64 {
         self.count.unwrap_or_default()
     }
 
-    fn set_count(&mut self, count: u32) {
+    /// Occurrences of `old` actually moved to `new`.
+    fn read_moved(&self) -> u64 {
+        self.moved.unwrap_or_default()
+    }
+
+    fn set_count(&mut self, count: u64, rule: &Rule, fraction: f64) {
         self.count = Some(count);
+        self.moved = Some(rule.scale(count, fraction));
+    }
+
+    /// Reports the transfer to `new` in `table` to [`explain`].
+    fn record(&self, table: Table, hooks: &mut Hooks<'_>)
+    where
+        O: AsRef<[char]>,
+        N: AsRef<[char]>,
+    {
+        hooks.record(
+            table,
+            self.new.as_ref(),
+            self.old.as_ref(),
+            Some(self.kind),
+            Pass::Boundary,
+            self.read_moved() as i64,
+        );
+    }
+
+    /// Fraction of `old`'s occurrences where the rule's context matches, at
+    /// the edge(s) that this expansion reaches past.
+    fn match_fraction<U: CorpusExt>(&self, corpus: &U, rule: &Rule) -> f64
+    where
+        O: AsRef<[char]>,
+    {
+        if !rule.has_context() {
+            return 1.0;
+        }
+        let old = self.old.as_ref();
+        let last = old.len() - 2;
+        match self.kind {
+            ExpansionKind::Left => match_fraction(corpus, rule, old, 0),
+            ExpansionKind::Right => match_fraction(corpus, rule, old, last),
+            ExpansionKind::Both => {
+                match_fraction(corpus, rule, old, 0) * match_fraction(corpus, rule, old, last)
+            }
+        }
     }
 }
 
-#[derive(PartialEq)]
-enum ExpansionKind {
+/// Which side(s) of an ngram a boundary expansion reaches past.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpansionKind {
     Left,
     Right,
     Both,
@@ -83,24 +233,59 @@ pub struct Expansions<N, S, L> {
     both: Option<ExpansionStruct<L, N>>,
 }
 
-impl<O, N> From<&ExpansionStruct<O, N>> for u32 {
+impl<O, N> From<&ExpansionStruct<O, N>> for u64 {
     fn from(exp: &ExpansionStruct<O, N>) -> Self {
         exp.read_count()
     }
 }
 
 impl<N, S, L> Expansions<N, S, L> {
-    fn sum(&self, kinds: &[ExpansionKind]) -> u32 {
-        fn unpack<O, N>(x: &Option<ExpansionStruct<O, N>>) -> Option<u32> {
+    fn sum(&self, kinds: &[ExpansionKind]) -> u64 {
+        fn unpack<O, N>(x: &Option<ExpansionStruct<O, N>>) -> Option<u64> {
             x.as_ref().map_or(Some(0), |x| Some(x.into()))
         }
+        self.sum_by(kinds, unpack, unpack)
+    }
+
+    fn sum_moved(&self, kinds: &[ExpansionKind]) -> u64 {
+        fn unpack<O, N>(x: &Option<ExpansionStruct<O, N>>) -> Option<u64> {
+            x.as_ref().map_or(Some(0), |x| Some(x.read_moved()))
+        }
+        self.sum_by(kinds, unpack, unpack)
+    }
+
+    /// Kind, `old` and moved count of each expansion, for [`explain`].
+    fn moved(&self) -> Vec<(ExpansionKind, Vec<char>, u64)>
+    where
+        S: AsRef<[char]>,
+        L: AsRef<[char]>,
+    {
+        let both = self
+            .both
+            .iter()
+            .map(|x| (x.kind, x.old.as_ref(), x.read_moved()));
+        let short = [&self.left, &self.right]
+            .into_iter()
+            .flatten()
+            .map(|x| (x.kind, x.old.as_ref(), x.read_moved()));
+        both.chain(short)
+            .map(|(kind, old, moved)| (kind, old.to_vec(), moved))
+            .collect()
+    }
+
+    fn sum_by(
+        &self,
+        kinds: &[ExpansionKind],
+        short: fn(&Option<ExpansionStruct<S, N>>) -> Option<u64>,
+        long: fn(&Option<ExpansionStruct<L, N>>) -> Option<u64>,
+    ) -> u64 {
         kinds
             .iter()
-            .filter_map(|k| -> Option<u32> {
+            .filter_map(|k| -> Option<u64> {
                 match k {
-                    ExpansionKind::Left => unpack(&self.left),
-                    ExpansionKind::Right => unpack(&self.right),
-                    ExpansionKind::Both => unpack(&self.both),
+                    ExpansionKind::Left => short(&self.left),
+                    ExpansionKind::Right => short(&self.right),
+                    ExpansionKind::Both => long(&self.both),
                 }
             })
             .sum()
@@ -116,27 +301,259 @@ trait Expand<N, S, L> {
 }
 
 pub trait GetCount<O, N> {
-    fn get_count<U: CorpusExt>(&self, corpus: &mut U) -> u32;
+    fn get_count<U: CorpusExt>(&self, corpus: &U) -> U::Count;
 }
 
 pub trait AdaptiveCorpusBase<N>: CorpusExt {
-    fn adapt_boundary_ngram<O: Debug>(
-        &mut self,
+    fn collect_boundary_ngram<O: Debug + AsRef<[char]>>(
+        &self,
+        rule: &Rule,
         exp: &mut Option<ExpansionStruct<O, N>>,
-        bcount: u32,
+        bcount: u64,
+        deltas: &mut Deltas,
+        hooks: &mut Hooks<'_>,
     ) where
         ExpansionStruct<O, N>: GetCount<O, N>;
 }
 
+/// What an order's loops check and report as they go.
+///
+/// The default does nothing, a token stops them at the next ngram once it's
+/// cancelled, and [`explain`]'s tracer records the transfers of one ngram.
+#[derive(Debug, Default)]
+pub struct Hooks<'a> {
+    token: Option<&'a CancelToken>,
+    tracer: Option<&'a mut Tracer>,
+}
+
+impl<'a> Hooks<'a> {
+    pub fn with_token(token: &'a CancelToken) -> Self {
+        Self {
+            token: Some(token),
+            tracer: None,
+        }
+    }
+
+    /// Errs once the token's been cancelled.
+    fn check(&self) -> Result<(), Cancelled> {
+        match self.token {
+            Some(token) if token.is_cancelled() => Err(Cancelled),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The [`Hooks`]' token was cancelled, the deltas collected so far are partial.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "adaptation was cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Adapting one order is collecting its [`Deltas`], then applying them.
 pub trait AdaptiveCorpus<N>: AdaptiveCorpusBase<N> {
-    fn adapt_ngrams(&mut self, old: [char; 2], new: [char; 2]);
-    fn adapt_boundary_ngrams(&mut self, old: [char; 2], new: [char; 2]);
-    fn adapt_interior_ngrams(&mut self, old: [char; 2], new: [char; 2]);
-    fn adapt_interior_ngram(
-        &mut self,
+    /// Returns whether any count changed.
+    fn adapt_ngrams(&mut self, rule: Rule) -> bool {
+        let mut deltas = Deltas::default();
+        self.collect_ngrams(rule, &mut deltas, &mut Hooks::default())
+            .expect("Nothing to cancel!");
+        !deltas.apply_undoable(self).is_empty()
+    }
+    fn collect_ngrams(
+        &self,
+        rule: Rule,
+        deltas: &mut Deltas,
+        hooks: &mut Hooks<'_>,
+    ) -> Result<(), Cancelled>;
+    fn collect_boundary_ngrams(
+        &self,
+        rule: Rule,
+        deltas: &mut Deltas,
+        hooks: &mut Hooks<'_>,
+    ) -> Result<(), Cancelled>;
+    fn collect_interior_ngrams(
+        &self,
+        rule: Rule,
+        deltas: &mut Deltas,
+        hooks: &mut Hooks<'_>,
+    ) -> Result<(), Cancelled>;
+    fn collect_interior_ngram(
+        &self,
+        rule: &Rule,
         old_idx: usize,
         old_ng: &[char],
         new_ng: &[char],
-        acc: &mut Vec<i32>,
+        deltas: &mut Deltas,
+        hooks: &mut Hooks<'_>,
     );
 }
+
+/// Orders adapted by [`Adapt::adapt`], lowest first.
+///
+/// Lower orders read the (still unadapted) tables of the two orders above
+/// them, so they have to go first.
+pub const ORDERS: [usize; 5] = [1, 2, 3, 4, 5];
+
+/// Tables that adapting `order` by `rule` reads from.
+///
+/// Rules with a context look one order further up than the expansions do, to
+/// see the neighbours of ngrams at the edge of the window. Only they pay for
+/// it: for orders 1 and 2 that's the next table up, which for order 2 is the
+/// pentagrams, eg. [`SplitCorpus::adapt`](crate::SplitCorpus::adapt) maps the
+/// largest table for one more order.
+pub fn tables_read(order: usize, rule: Rule) -> &'static [Table] {
+    match (order, rule.has_context()) {
+        (1, false) => &[Table::Chars, Table::Bigrams, Table::Trigrams],
+        (1, true) => &[
+            Table::Chars,
+            Table::Bigrams,
+            Table::Trigrams,
+            Table::Quadgrams,
+        ],
+        (2, false) => &[Table::Bigrams, Table::Trigrams, Table::Quadgrams],
+        (2, true) => &[
+            Table::Bigrams,
+            Table::Trigrams,
+            Table::Quadgrams,
+            Table::Pentagrams,
+        ],
+        (3, _) => &[
+            Table::Trigrams,
+            Table::Skipgrams,
+            Table::Quadgrams,
+            Table::Pentagrams,
+        ],
+        (4, _) => &[Table::Quadgrams, Table::Pentagrams],
+        (5, _) => &[Table::Pentagrams],
+        _ => panic!("No such order: {order}"),
+    }
+}
+
+/// Tables that adapting `order` writes to.
+pub fn tables_written(order: usize) -> &'static [Table] {
+    match order {
+        1 => &[Table::Chars],
+        2 => &[Table::Bigrams],
+        3 => &[Table::Trigrams, Table::Skipgrams],
+        4 => &[Table::Quadgrams],
+        5 => &[Table::Pentagrams],
+        _ => panic!("No such order: {order}"),
+    }
+}
+
+/// Picks the right [`AdaptiveCorpus`] impl by order, so callers don't have to.
+///
+/// ```ignore
+/// corpus.adapt(Rule::new(['h', 'e'], ['h', '†']));
+/// ```
+pub trait Adapt:
+    AdaptiveCorpus<[char; 1]>
+    + AdaptiveCorpus<[char; 2]>
+    + AdaptiveCorpus<[char; 3]>
+    + AdaptiveCorpus<[char; 4]>
+    + AdaptiveCorpus<[char; 5]>
+{
+    /// Returns whether any count changed.
+    fn adapt_order(&mut self, order: usize, rule: Rule) -> bool {
+        match order {
+            1 => <Self as AdaptiveCorpus<[char; 1]>>::adapt_ngrams(self, rule),
+            2 => <Self as AdaptiveCorpus<[char; 2]>>::adapt_ngrams(self, rule),
+            3 => <Self as AdaptiveCorpus<[char; 3]>>::adapt_ngrams(self, rule),
+            4 => <Self as AdaptiveCorpus<[char; 4]>>::adapt_ngrams(self, rule),
+            5 => <Self as AdaptiveCorpus<[char; 5]>>::adapt_ngrams(self, rule),
+            _ => panic!("No such order: {order}"),
+        }
+    }
+
+    /// Adds the changes [`Adapt::adapt_order`] would make to `deltas`, or
+    /// some of them if `hooks` cancels it.
+    fn collect_order(
+        &self,
+        order: usize,
+        rule: Rule,
+        deltas: &mut Deltas,
+        hooks: &mut Hooks<'_>,
+    ) -> Result<(), Cancelled> {
+        match order {
+            1 => <Self as AdaptiveCorpus<[char; 1]>>::collect_ngrams(self, rule, deltas, hooks),
+            2 => <Self as AdaptiveCorpus<[char; 2]>>::collect_ngrams(self, rule, deltas, hooks),
+            3 => <Self as AdaptiveCorpus<[char; 3]>>::collect_ngrams(self, rule, deltas, hooks),
+            4 => <Self as AdaptiveCorpus<[char; 4]>>::collect_ngrams(self, rule, deltas, hooks),
+            5 => <Self as AdaptiveCorpus<[char; 5]>>::collect_ngrams(self, rule, deltas, hooks),
+            _ => panic!("No such order: {order}"),
+        }
+    }
+
+    /// Returns whether any count changed.
+    fn adapt(&mut self, rule: Rule) -> bool {
+        let mut changed = false;
+        for order in ORDERS {
+            changed |= self.adapt_order(order, rule);
+        }
+        changed
+    }
+
+    /// Adapts every order, the way `strategy` says to, returns whether any
+    /// count changed.
+    fn adapt_with(&mut self, rule: Rule, strategy: Strategy) -> bool {
+        match strategy {
+            Strategy::PerOrder => self.adapt(rule),
+            Strategy::Marginal => {
+                let mut deltas = Deltas::default();
+                self.collect_marginal(rule, &mut deltas);
+                !deltas.apply_undoable(self).is_empty()
+            }
+        }
+    }
+
+    /// Collects the pentagrams' deltas, and moves them onto lower orders, see
+    /// [`marginal`].
+    ///
+    /// A rule that changes its first char would get the wrong quadgrams, so
+    /// it's collected per order instead, with a warning.
+    fn collect_marginal(&self, rule: Rule, deltas: &mut Deltas) {
+        if rule.new[0] != rule.old[0] {
+            warn!(
+                ?rule,
+                "changes its first char, collecting per order instead"
+            );
+            for order in ORDERS {
+                self.collect_order(order, rule, deltas, &mut Hooks::default())
+                    .expect("Nothing to cancel!");
+            }
+            return;
+        }
+        let mut pentagrams = Deltas::default();
+        self.collect_order(5, rule, &mut pentagrams, &mut Hooks::default())
+            .expect("Nothing to cancel!");
+        marginal::marginalize(self, &pentagrams, deltas);
+    }
+
+    /// What [`Adapt::adapt`] would change, without changing it.
+    fn dry_run(&self, rule: Rule) -> DeltaSet {
+        let orders = ORDERS
+            .iter()
+            .map(|&order| {
+                let mut deltas = Deltas::default();
+                self.collect_order(order, rule, &mut deltas, &mut Hooks::default())
+                    .expect("Nothing to cancel!");
+                (order, deltas)
+            })
+            .collect();
+        DeltaSet { rule, orders }
+    }
+}
+
+impl<U> Adapt for U where
+    U: AdaptiveCorpus<[char; 1]>
+        + AdaptiveCorpus<[char; 2]>
+        + AdaptiveCorpus<[char; 3]>
+        + AdaptiveCorpus<[char; 4]>
+        + AdaptiveCorpus<[char; 5]>
+{
+}
@@ -2,39 +2,44 @@
 //
 // SPDX-License-Identifier: GPL-3.0-only
 
-use crate::CorpusExt;
+use crate::adaptive_corpus::context::interior_fraction;
 use crate::adaptive_corpus::*;
-use kc::Corpus;
+use crate::count::Count;
+use crate::{CorpusExt, Table};
 
 impl GetCount<[char; 3], [char; 2]> for ExpansionStruct<[char; 3], [char; 2]> {
     /// Count trigrams.
-    fn get_count<U: CorpusExt>(&self, corpus: &mut U) -> u32 {
-        let idx = corpus.corpus_trigram(&self.old);
-        corpus.get_trigrams()[idx]
+    fn get_count<U: CorpusExt>(&self, corpus: &U) -> U::Count {
+        corpus.count_ngram(&self.old)
     }
 }
 
 impl GetCount<[char; 4], [char; 2]> for ExpansionStruct<[char; 4], [char; 2]> {
     /// Count quadgrams.
-    fn get_count<U: CorpusExt>(&self, corpus: &mut U) -> u32 {
-        let idx = corpus.corpus_quadgram(&self.old);
-        corpus.get_quadgrams()[idx]
+    fn get_count<U: CorpusExt>(&self, corpus: &U) -> U::Count {
+        corpus.count_ngram(&self.old)
     }
 }
 
 impl<U: CorpusExt> AdaptiveCorpusBase<[char; 2]> for U {
-    fn adapt_boundary_ngram<O>(
-        &mut self,
+    fn collect_boundary_ngram<O>(
+        &self,
+        rule: &Rule,
         exp: &mut Option<ExpansionStruct<O, [char; 2]>>,
-        bcount: u32,
+        bcount: u64,
+        deltas: &mut Deltas,
+        hooks: &mut Hooks<'_>,
     ) where
         ExpansionStruct<O, [char; 2]>: GetCount<O, [char; 2]>,
+        O: AsRef<[char]>,
     {
         if let Some(exp) = exp {
-            exp.set_count(exp.get_count(self) - bcount);
+            let fraction = exp.match_fraction(self, rule);
+            exp.set_count(exp.get_count(self).into() - bcount, rule, fraction);
 
             let idx = self.corpus_bigram(&exp.new);
-            self.get_bigrams()[idx] += exp.read_count();
+            deltas.add(Table::Bigrams, idx, exp.read_moved().delta());
+            exp.record(Table::Bigrams, hooks);
         }
     }
 }
@@ -50,6 +55,7 @@ impl Expand<[char; 2], [char; 3], [char; 4]> for [char; 2] {
         // If the bigram starts with the old bigram suffix, left
         if self[0] == old[1] {
             left = Some(ExpansionStruct::new(
+                ExpansionKind::Left,
                 [old[0], self[0], self[1]],
                 [new[1], self[1]],
             ));
@@ -58,6 +64,7 @@ impl Expand<[char; 2], [char; 3], [char; 4]> for [char; 2] {
         // If the bigram ends with the old bigram prefix, right
         if self[1] == old[0] {
             right = Some(ExpansionStruct::new(
+                ExpansionKind::Right,
                 [self[0], self[1], old[1]],
                 [self[0], new[0]],
             ));
@@ -65,6 +72,7 @@ impl Expand<[char; 2], [char; 3], [char; 4]> for [char; 2] {
             // If both, both
             if let Some(ref left) = left {
                 both = Some(ExpansionStruct::new(
+                    ExpansionKind::Both,
                     [left.old[0], left.old[1], left.old[2], old[1]],
                     [left.new[0], new[0]],
                 ));
@@ -78,69 +86,88 @@ impl Expand<[char; 2], [char; 3], [char; 4]> for [char; 2] {
 /// Methods for adapting bigram frequencies to reflect bigram substitutions.
 ///
 /// # Debugging
+/// - [`explain`](crate::explain) lists the transfers into and out of one ngram
 /// - See commented code in module `test::si_compare_all_bigrams`
 /// ```ignore
 /// if tg == &['†', 'a', 'h'] { ... }
 /// ```
-impl AdaptiveCorpus<[char; 2]> for Corpus {
-    fn adapt_ngrams(&mut self, old: [char; 2], new: [char; 2]) {
-        // self.adapt_interior_ngrams(old, new);
-        <Corpus as AdaptiveCorpus<[char; 2]>>::adapt_interior_ngrams(self, old, new);
-        // self.adapt_boundary_ngrams(old, new);
-        <Corpus as AdaptiveCorpus<[char; 2]>>::adapt_boundary_ngrams(self, old, new);
+impl<U: CorpusExt> AdaptiveCorpus<[char; 2]> for U {
+    fn collect_ngrams(
+        &self,
+        rule: Rule,
+        deltas: &mut Deltas,
+        hooks: &mut Hooks<'_>,
+    ) -> Result<(), Cancelled> {
+        // self.collect_interior_ngrams(rule, deltas, hooks)?;
+        <Self as AdaptiveCorpus<[char; 2]>>::collect_interior_ngrams(self, rule, deltas, hooks)?;
+        // self.collect_boundary_ngrams(rule, deltas, hooks)
+        <Self as AdaptiveCorpus<[char; 2]>>::collect_boundary_ngrams(self, rule, deltas, hooks)
     }
 
-    fn adapt_boundary_ngrams(&mut self, old: [char; 2], new: [char; 2]) {
-        let num_bigrams = self.get_bigrams().len();
-        for i in 0..num_bigrams {
+    fn collect_boundary_ngrams(
+        &self,
+        rule: Rule,
+        deltas: &mut Deltas,
+        hooks: &mut Hooks<'_>,
+    ) -> Result<(), Cancelled> {
+        let Rule { old, new, .. } = rule;
+        for i in self.nonzero(Table::Bigrams) {
+            hooks.check()?;
             let bg = self.uncorpus_bigram(i);
             let mut exps = [bg[0], bg[1]].expand(old, new);
 
-            self.adapt_boundary_ngram(&mut exps.both, 0);
+            self.collect_boundary_ngram(&rule, &mut exps.both, 0, deltas, hooks);
             let bcount = exps.sum(&[ExpansionKind::Both]);
-            self.adapt_boundary_ngram(&mut exps.left, bcount);
-            self.adapt_boundary_ngram(&mut exps.right, bcount);
+            self.collect_boundary_ngram(&rule, &mut exps.left, bcount, deltas, hooks);
+            self.collect_boundary_ngram(&rule, &mut exps.right, bcount, deltas, hooks);
 
-            let sum = exps.sum(&[ExpansionKind::Left, ExpansionKind::Right, ExpansionKind::Both]);
+            let sum =
+                exps.sum_moved(&[ExpansionKind::Left, ExpansionKind::Right, ExpansionKind::Both]);
 
-            self.bigrams[i] -= sum;
+            deltas.add(Table::Bigrams, i, -sum.delta());
+            if hooks.tracing() {
+                hooks.record_boundary_sum(Table::Bigrams, &bg, &bg, 1.0, &exps.moved());
+            }
         }
+        Ok(())
     }
 
-    fn adapt_interior_ngrams(&mut self, old: [char; 2], new: [char; 2]) {
-        let num_bigrams = self.get_bigrams().len();
-        let mut acc = vec![0; num_bigrams];
-
-        for i in 0..num_bigrams {
-            if self.bigrams[i] == 0 {
-                continue;
-            }
+    fn collect_interior_ngrams(
+        &self,
+        rule: Rule,
+        deltas: &mut Deltas,
+        hooks: &mut Hooks<'_>,
+    ) -> Result<(), Cancelled> {
+        let Rule { old, new, .. } = rule;
+
+        for i in self.nonzero(Table::Bigrams) {
+            hooks.check()?;
             let bg = self.uncorpus_bigram(i);
             if bg[0] == old[0] && bg[1] == old[1] {
-                // self.adapt_interior_ngram(i, &bg[..], &[new[0], new[1]]);
+                // self.collect_interior_ngram(i, &bg[..], &[new[0], new[1]]);
                 #[rustfmt::skip]
-                <Corpus as AdaptiveCorpus<[char; 2]>>::adapt_interior_ngram(self, i, &bg[..], &[new[0], new[1]], &mut acc);
+                <Self as AdaptiveCorpus<[char; 2]>>::collect_interior_ngram(self, &rule, i, &bg[..], &[new[0], new[1]], deltas, hooks);
             }
         }
-
-        for (a, b) in self.get_bigrams().iter_mut().zip(&acc) {
-            *a = a.checked_add_signed(*b).expect("Overflow!");
-        }
+        Ok(())
     }
 
-    fn adapt_interior_ngram(
-        &mut self,
+    fn collect_interior_ngram(
+        &self,
+        rule: &Rule,
         old_idx: usize,
         old_ng: &[char],
         new_ng: &[char],
-        acc: &mut Vec<i32>,
+        deltas: &mut Deltas,
+        hooks: &mut Hooks<'_>,
     ) {
-        let _ = old_ng;
-
-        let freq = self.get_bigrams()[old_idx];
-        acc[old_idx] = acc[old_idx].checked_sub_unsigned(freq).expect("Overflow!");
+        let fraction = interior_fraction(self, rule, old_ng, new_ng);
+        let freq =
+            U::Count::narrow(rule.scale(self.count_at(Table::Bigrams, old_idx).into(), fraction));
+        deltas.add(Table::Bigrams, old_idx, -freq.delta());
 
         let new_idx = self.corpus_bigram(&[new_ng[0], new_ng[1]]);
-        acc[new_idx] = acc[new_idx].checked_add_unsigned(freq).expect("Overflow!");
+        deltas.add(Table::Bigrams, new_idx, freq.delta());
+        hooks.record_interior(Table::Bigrams, old_ng, new_ng, freq.delta());
     }
 }
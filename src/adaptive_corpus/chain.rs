@@ -0,0 +1,254 @@
+// Copyright 2025 antlers <antlers@illucid.net>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Rules whose triggers contain the placeholders of other rules.
+//!
+//! After `he -> h†`, a layout may also define `†r -> †‡`: the second rule only
+//! ever matches the keystrokes written by the first, so it has to be applied
+//! after it. A [`RuleSet`] applies every rule after the rules that create the
+//! placeholders in its trigger, keeping the given order otherwise.
+//!
+//! Rules that feed each other (eg. `†r -> †‡` and `‡e -> ‡†`) have no such
+//! order. [`Resolution::FixedPoint`] applies them in the given order instead,
+//! and repeats the whole set until it stops changing anything. It's an error
+//! if it's still changing after `max_passes`. A rule with a [`Rule::rate`]
+//! below 1 would move a share of what it left behind on every pass, so it's an
+//! error too.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::fs;
+//! use kc::Corpus;
+//! use km_adaptive_corpus::{Resolution, Rule, RuleSet};
+//!
+//! let b = fs::read("./corpora/shai-iweb.corpus").unwrap();
+//! let mut corpus: Corpus = rmp_serde::from_slice(&b).unwrap();
+//! let rules = RuleSet::new(vec![
+//!     Rule::new(['†', 'r'], ['†', '‡']),
+//!     Rule::new(['h', 'e'], ['h', '†']),
+//! ]);
+//! rules.adapt(&mut corpus, Resolution::Ordered).unwrap();
+//! ```
+
+use crate::AdaptCache;
+use crate::adaptive_corpus::progress::adapt_uncancellable;
+use crate::adaptive_corpus::{Adapt, Hooks, Rule};
+
+use std::fmt;
+
+use tracing::{debug, instrument};
+
+/// How a [`RuleSet`] decides what order to apply its rules in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    /// Each rule after the rules creating its trigger's placeholders, it's an
+    /// error if they depend on each other.
+    Ordered,
+    /// The given order, repeated until a pass changes nothing, it's an error
+    /// if `max_passes` aren't enough, or any rule's rate is below 1.
+    FixedPoint { max_passes: usize },
+}
+
+/// Rules that depend on each other's placeholders, so can't be ordered.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CycleError {
+    pub rules: Vec<Rule>,
+}
+
+impl fmt::Display for CycleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "rules depend on each other's placeholders:")?;
+        for rule in &self.rules {
+            write!(
+                f,
+                " {}{}->{}{}",
+                rule.old[0], rule.old[1], rule.new[0], rule.new[1]
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for CycleError {}
+
+/// Why a [`RuleSet`] couldn't be applied.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChainError {
+    Cycle(CycleError),
+    /// The last of `max_passes` still changed the corpus, which is left as
+    /// that pass left it.
+    NoFixedPoint {
+        max_passes: usize,
+    },
+    /// [`Resolution::FixedPoint`] would apply `rule` to what it left behind
+    /// again on every pass.
+    PartialRate {
+        rule: Rule,
+    },
+}
+
+impl fmt::Display for ChainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChainError::Cycle(e) => write!(f, "{e}"),
+            ChainError::NoFixedPoint { max_passes } => {
+                write!(
+                    f,
+                    "rules still changed the corpus after {max_passes} passes"
+                )
+            }
+            ChainError::PartialRate { rule } => write!(
+                f,
+                "{}{}->{}{} has a rate of {}, fixed points need every rule at 1",
+                rule.old[0],
+                rule.old[1],
+                rule.new[0],
+                rule.new[1],
+                rule.rate()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ChainError {}
+
+impl From<CycleError> for ChainError {
+    fn from(e: CycleError) -> Self {
+        ChainError::Cycle(e)
+    }
+}
+
+/// An ordered list of rules, possibly chained through their placeholders.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RuleSet {
+    pub rules: Vec<Rule>,
+    /// Where [`RuleSet::adapt`] looks its results up, see [`AdaptCache`].
+    pub cache: Option<AdaptCache>,
+}
+
+impl RuleSet {
+    pub fn new(rules: Vec<Rule>) -> Self {
+        Self { rules, cache: None }
+    }
+
+    pub fn with_cache(self, cache: AdaptCache) -> Self {
+        Self {
+            cache: Some(cache),
+            ..self
+        }
+    }
+
+    /// Chars that `rule` writes but doesn't read, eg. `†` for `he -> h†`.
+    pub fn placeholders(rule: &Rule) -> Vec<char> {
+        let mut placeholders: Vec<char> = rule
+            .new
+            .iter()
+            .copied()
+            .filter(|c| !rule.old.contains(c))
+            .collect();
+        placeholders.dedup();
+        placeholders
+    }
+
+    /// Whether `rule` reads a placeholder written by `other`.
+    fn depends_on(rule: &Rule, other: &Rule) -> bool {
+        Self::placeholders(other)
+            .iter()
+            .any(|c| rule.old.contains(c))
+    }
+
+    /// The rules in the order [`Resolution::Ordered`] applies them.
+    ///
+    /// Each rule comes after every rule whose placeholders its trigger
+    /// contains, otherwise earlier rules come first.
+    pub fn ordered(&self) -> Result<Vec<Rule>, CycleError> {
+        Ok(self.order()?.into_iter().map(|i| self.rules[i]).collect())
+    }
+
+    /// Indices of the rules, in the order [`RuleSet::ordered`] returns them.
+    fn order(&self) -> Result<Vec<usize>, CycleError> {
+        let n = self.rules.len();
+        let deps: Vec<Vec<usize>> = (0..n)
+            .map(|i| {
+                (0..n)
+                    .filter(|&j| j != i && Self::depends_on(&self.rules[i], &self.rules[j]))
+                    .collect()
+            })
+            .collect();
+
+        let mut done = vec![false; n];
+        let mut order = Vec::with_capacity(n);
+        while order.len() < n {
+            let next = (0..n).find(|&i| !done[i] && deps[i].iter().all(|&j| done[j]));
+            match next {
+                Some(i) => {
+                    done[i] = true;
+                    order.push(i);
+                }
+                None => {
+                    let rules = (0..n)
+                        .filter(|&i| !done[i])
+                        .map(|i| self.rules[i])
+                        .collect();
+                    return Err(CycleError { rules });
+                }
+            }
+        }
+        Ok(order)
+    }
+
+    /// Applies every rule to `corpus`, returns the number of passes taken.
+    ///
+    /// [`Resolution::Ordered`] always takes one pass. A rule that reads its
+    /// own placeholder (eg. `†e -> ††`) is only applied once per pass. With a
+    /// cache, a hit applies what was stored instead.
+    #[instrument(level = "debug", skip(self, corpus))]
+    pub fn adapt<U: Adapt>(
+        &self,
+        corpus: &mut U,
+        resolution: Resolution,
+    ) -> Result<usize, ChainError> {
+        if let Some(cache) = &self.cache {
+            return cache.adapt(self, corpus, resolution);
+        }
+        adapt_uncancellable(corpus, self, resolution, &mut Hooks::default())
+            .map(|(passes, _)| passes)
+    }
+
+    /// Calls `apply` with the index of each rule to apply, in order, stopping
+    /// at the first error it returns. `apply` returns whether the rule changed
+    /// any count, a fixed point is a pass where none did.
+    pub(crate) fn run<U: Adapt, E: From<ChainError>>(
+        &self,
+        corpus: &mut U,
+        resolution: Resolution,
+        mut apply: impl FnMut(&mut U, usize) -> Result<bool, E>,
+    ) -> Result<usize, E> {
+        match resolution {
+            Resolution::Ordered => {
+                for i in self.order().map_err(ChainError::Cycle)? {
+                    apply(corpus, i)?;
+                }
+                Ok(1)
+            }
+            Resolution::FixedPoint { max_passes } => {
+                if let Some(&rule) = self.rules.iter().find(|rule| rule.rate() < 1.0) {
+                    return Err(ChainError::PartialRate { rule }.into());
+                }
+                for pass in 1..=max_passes {
+                    let mut changed = false;
+                    for i in 0..self.rules.len() {
+                        changed |= apply(corpus, i)?;
+                    }
+                    if !changed {
+                        debug!(pass, "fixed point");
+                        return Ok(pass);
+                    }
+                }
+                Err(ChainError::NoFixedPoint { max_passes }.into())
+            }
+        }
+    }
+}
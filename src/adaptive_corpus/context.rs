@@ -0,0 +1,135 @@
+// Copyright 2025 antlers <antlers@illucid.net>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Conditions on the chars around a rule's `old` bigram.
+//!
+//! When the neighbouring char is inside the ngram being adapted, the condition
+//! is checked directly. When it falls outside, the fraction of occurrences that
+//! match is estimated from the next order up, eg. `*he` followed by a space is
+//! looked up in the quadgrams. Windows are capped at four chars so lookups
+//! never go past the pentagrams, which makes the estimate Markov-ish for
+//! pentagrams and longer expansions.
+
+use crate::CorpusExt;
+use crate::adaptive_corpus::Rule;
+
+use serde::{Deserialize, Serialize};
+
+use std::iter;
+
+/// A class of chars that a [`Context`] can refer to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Class {
+    /// Whitespace, ASCII punctuation, and control chars (including the
+    /// corpus's placeholder for invalid chars).
+    Boundary,
+    /// Anything else, including magic placeholders like `†`.
+    Word,
+    Char(char),
+}
+
+impl Class {
+    pub fn contains(self, c: char) -> bool {
+        let boundary = c.is_whitespace() || c.is_ascii_punctuation() || c.is_control();
+        match self {
+            Class::Boundary => boundary,
+            Class::Word => !boundary,
+            Class::Char(x) => c == x,
+        }
+    }
+}
+
+/// What the char before or after a rule's `old` bigram must (not) be.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub enum Context {
+    #[default]
+    Any,
+    Is(Class),
+    IsNot(Class),
+}
+
+impl Context {
+    pub fn allows(self, c: char) -> bool {
+        match self {
+            Context::Any => true,
+            Context::Is(class) => class.contains(c),
+            Context::IsNot(class) => !class.contains(c),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Side {
+    Before,
+    After,
+}
+
+/// Fraction of occurrences of `rule.old` at `ngram[at..at + 2]` whose context matches.
+pub(crate) fn match_fraction<U: CorpusExt>(
+    corpus: &U,
+    rule: &Rule,
+    ngram: &[char],
+    at: usize,
+) -> f64 {
+    let before = match at.checked_sub(1) {
+        Some(k) => f64::from(u8::from(rule.before.allows(ngram[k]))),
+        None => {
+            let window = &ngram[..ngram.len().min(4)];
+            edge_fraction(corpus, window, Side::Before, rule.before)
+        }
+    };
+    let after = match ngram.get(at + 2) {
+        Some(&c) => f64::from(u8::from(rule.after.allows(c))),
+        None => {
+            let window = &ngram[ngram.len().saturating_sub(4)..];
+            edge_fraction(corpus, window, Side::After, rule.after)
+        }
+    };
+    before * after
+}
+
+/// Fraction of an interior substitution `old_ng -> new_ng` whose contexts match.
+///
+/// Matches are wherever `old_ng` has `rule.old` and `new_ng` has `rule.new`.
+pub(crate) fn interior_fraction<U: CorpusExt>(
+    corpus: &U,
+    rule: &Rule,
+    old_ng: &[char],
+    new_ng: &[char],
+) -> f64 {
+    if !rule.has_context() {
+        return 1.0;
+    }
+    (0..old_ng.len().saturating_sub(1))
+        .filter(|&k| old_ng[k..k + 2] == rule.old && new_ng[k..k + 2] == rule.new)
+        .map(|k| match_fraction(corpus, rule, old_ng, k))
+        .product()
+}
+
+/// Fraction of `window`'s occurrences whose neighbour on `side` is allowed by `ctx`.
+fn edge_fraction<U: CorpusExt>(corpus: &U, window: &[char], side: Side, ctx: Context) -> f64 {
+    if ctx == Context::Any {
+        return 1.0;
+    }
+
+    let (mut allowed, mut total) = (0u64, 0u64);
+    for i in 0..corpus.num_chars() {
+        let c = corpus.uncorpus_unigram(i);
+        let ngram: Vec<char> = match side {
+            Side::Before => iter::once(c).chain(window.iter().copied()).collect(),
+            Side::After => window.iter().copied().chain(iter::once(c)).collect(),
+        };
+        let count: u64 = corpus.count_ngram(&ngram).into();
+        total += count;
+        if ctx.allows(c) {
+            allowed += count;
+        }
+    }
+
+    if total == 0 {
+        1.0
+    } else {
+        allowed as f64 / total as f64
+    }
+}
@@ -0,0 +1,119 @@
+// Copyright 2025 antlers <antlers@illucid.net>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! What a rule would change, without changing anything.
+//!
+//! Adapting an order is two steps: collecting the changes to its tables into
+//! sparse [`Deltas`], then applying them. [`Adapt::dry_run`] only does the
+//! first, for every order, and returns a [`DeltaSet`] that can be inspected,
+//! then [committed](DeltaSet::commit) or dropped.
+//!
+//! Every order only reads tables that lower orders don't write, so collecting
+//! them all against the same corpus gives the same result as adapting them in
+//! turn.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::fs;
+//! use kc::Corpus;
+//! use km_adaptive_corpus::{Adapt, Rule, Table};
+//!
+//! let b = fs::read("./corpora/shai-iweb.corpus").unwrap();
+//! let mut corpus: Corpus = rmp_serde::from_slice(&b).unwrap();
+//! let deltas = corpus.dry_run(Rule::new(['h', 'e'], ['h', '†']));
+//! let idx = corpus.corpus_bigram(&['h', '†']);
+//! println!("h†: +{}", deltas.get(Table::Bigrams, idx));
+//! deltas.commit(&mut corpus);
+//! ```
+
+use crate::adaptive_corpus::{Adapt, Rule};
+use crate::count::Count;
+use crate::{CorpusExt, Table};
+
+use std::collections::HashMap;
+
+/// Sparse changes to a corpus's tables, by table and index.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Deltas(HashMap<(Table, usize), i64>);
+
+impl Deltas {
+    /// Counts whose deltas cancel out are dropped, so empty deltas change
+    /// nothing.
+    pub fn add(&mut self, table: Table, idx: usize, delta: i64) {
+        let d = self.0.entry((table, idx)).or_default();
+        *d = d.checked_add(delta).expect("Overflow!");
+        if *d == 0 {
+            self.0.remove(&(table, idx));
+        }
+    }
+
+    pub fn get(&self, table: Table, idx: usize) -> i64 {
+        self.0.get(&(table, idx)).copied().unwrap_or_default()
+    }
+
+    /// Every changed count, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (Table, usize, i64)> + '_ {
+        self.0.iter().map(|(&(table, idx), &d)| (table, idx, d))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Adds every delta to `corpus`, counts saturate at zero.
+    pub fn apply<U: CorpusExt + ?Sized>(&self, corpus: &mut U) {
+        for (table, idx, d) in self.iter() {
+            corpus.add_at(table, idx, d);
+        }
+    }
+
+    /// Like [`Deltas::apply`], but returns the deltas that undo it, counts
+    /// that saturated included.
+    pub fn apply_undoable<U: CorpusExt + ?Sized>(&self, corpus: &mut U) -> Deltas {
+        let mut undo = Deltas::default();
+        for (table, idx, d) in self.iter() {
+            let before = corpus.count_at(table, idx).delta();
+            corpus.add_at(table, idx, d);
+            let after = corpus.count_at(table, idx).delta();
+            undo.add(table, idx, before - after);
+        }
+        undo
+    }
+}
+
+/// The deltas of adapting every order by one rule, see [`Adapt::dry_run`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeltaSet {
+    pub rule: Rule,
+    /// By order, lowest first.
+    pub orders: Vec<(usize, Deltas)>,
+}
+
+impl DeltaSet {
+    pub fn order(&self, order: usize) -> Option<&Deltas> {
+        self.orders
+            .iter()
+            .find(|(o, _)| *o == order)
+            .map(|(_, d)| d)
+    }
+
+    /// The change to one count, summed over every order that writes `table`.
+    pub fn get(&self, table: Table, idx: usize) -> i64 {
+        self.orders.iter().map(|(_, d)| d.get(table, idx)).sum()
+    }
+
+    /// Applies the deltas, as [`Adapt::adapt`] would have.
+    ///
+    /// Only makes sense on the corpus they were collected from.
+    pub fn commit<U: CorpusExt>(&self, corpus: &mut U) {
+        for (_, deltas) in &self.orders {
+            deltas.apply(corpus);
+        }
+    }
+}
@@ -0,0 +1,223 @@
+// Copyright 2025 antlers <antlers@illucid.net>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Where an adapted ngram's count came from.
+//!
+//! [`explain`] adapts a corpus as [`RuleSet::adapt`] would, but also records
+//! every transfer into or out of one ngram of one table: the ngram it was
+//! read from, the [`ExpansionKind`] (for boundary transfers), the rule, and
+//! the amount. They add up to the change in the ngram's count, short of
+//! saturation.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::fs;
+//! use kc::Corpus;
+//! use km_adaptive_corpus::{Resolution, Rule, RuleSet, Table, explain};
+//!
+//! let b = fs::read("./corpora/shai-iweb.corpus").unwrap();
+//! let mut corpus: Corpus = rmp_serde::from_slice(&b).unwrap();
+//! let rules = RuleSet::new(vec![Rule::new(['h', 'e'], ['h', '†'])]);
+//! let explanation = explain(
+//!     &mut corpus,
+//!     &rules,
+//!     Resolution::Ordered,
+//!     Table::Trigrams,
+//!     &['e', 'h', '†'],
+//! )
+//! .unwrap();
+//! for c in &explanation.contributions {
+//!     println!("{:?} {:?} {:?}: {}", c.source, c.kind, c.pass, c.amount);
+//! }
+//! ```
+
+use crate::adaptive_corpus::chain::{ChainError, Resolution, RuleSet};
+use crate::adaptive_corpus::progress::adapt_uncancellable;
+use crate::adaptive_corpus::{Adapt, ExpansionKind, Hooks, split_boundary_sum};
+use crate::{CorpusExt, Table};
+
+use tracing::instrument;
+
+/// Which half of an order's adaptation made a transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pass {
+    /// Ngrams that contain the rule's trigger, eg. `the -> th†`.
+    Interior,
+    /// Ngrams next to a trigger, eg. `eat` after `h` becoming `†at`.
+    Boundary,
+}
+
+/// One transfer into or out of the explained ngram.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Contribution {
+    /// Index of the rule in the [`RuleSet`].
+    pub rule: usize,
+    /// The [`Resolution::FixedPoint`] pass, starting at 1.
+    pub round: usize,
+    /// The ngram whose count was read, eg. the pentagram of a boundary
+    /// expansion.
+    pub source: Vec<char>,
+    /// `None` for interior transfers.
+    pub kind: Option<ExpansionKind>,
+    pub pass: Pass,
+    pub amount: i64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Explanation {
+    pub table: Table,
+    pub ngram: Vec<char>,
+    pub before: u64,
+    pub after: u64,
+    pub contributions: Vec<Contribution>,
+}
+
+impl Explanation {
+    /// Sum of the contributions, `after - before` unless a count saturated.
+    ///
+    /// Contributions are the amounts actually moved, already scaled by their
+    /// rule's [`Rule::rate`].
+    ///
+    /// [`Rule::rate`]: crate::Rule::rate
+    pub fn total(&self) -> i64 {
+        self.contributions.iter().map(|c| c.amount).sum()
+    }
+}
+
+/// What [`explain`] passes down in [`Hooks`], to collect one ngram's
+/// transfers.
+#[derive(Debug)]
+pub(crate) struct Tracer {
+    table: Table,
+    ngram: Vec<char>,
+    rule: usize,
+    round: usize,
+    contributions: Vec<Contribution>,
+}
+
+impl Hooks<'_> {
+    /// Whether [`explain`] is running, so callers can skip building what
+    /// they'd record.
+    pub(crate) fn tracing(&self) -> bool {
+        self.tracer.is_some()
+    }
+
+    /// Records later transfers as made by rule `rule` in pass `round`.
+    pub(crate) fn trace_rule(&mut self, rule: usize, round: usize) {
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.rule = rule;
+            tracer.round = round;
+        }
+    }
+
+    /// Records a transfer of `amount` into `ngram` of `table`.
+    pub(crate) fn record(
+        &mut self,
+        table: Table,
+        ngram: &[char],
+        source: &[char],
+        kind: Option<ExpansionKind>,
+        pass: Pass,
+        amount: i64,
+    ) {
+        if let Some(tracer) = self.tracer.as_mut()
+            && amount != 0
+            && tracer.table == table
+            && tracer.ngram == ngram
+        {
+            tracer.contributions.push(Contribution {
+                rule: tracer.rule,
+                round: tracer.round,
+                source: source.to_vec(),
+                kind,
+                pass,
+                amount,
+            });
+        }
+    }
+
+    /// Records `amount` moving from `old` to `new` in `table`.
+    pub(crate) fn record_interior(
+        &mut self,
+        table: Table,
+        old: &[char],
+        new: &[char],
+        amount: i64,
+    ) {
+        self.record(table, old, old, None, Pass::Interior, -amount);
+        self.record(table, new, old, None, Pass::Interior, amount);
+    }
+
+    /// Records a boundary sum being taken back out, `fraction` of it from the
+    /// substituted ngram `moved_from` and the rest from `kept_on`.
+    ///
+    /// Each expansion's share is rounded cumulatively, so that the shares add up
+    /// to what [`split_boundary_sum`] takes from each.
+    pub(crate) fn record_boundary_sum(
+        &mut self,
+        table: Table,
+        moved_from: &[char],
+        kept_on: &[char],
+        fraction: f64,
+        expansions: &[(ExpansionKind, Vec<char>, u64)],
+    ) {
+        let (mut sum, mut moved) = (0, 0);
+        for (kind, source, count) in expansions {
+            sum += count;
+            let (total_moved, _) = split_boundary_sum(sum, fraction);
+            let share = total_moved - moved;
+            moved = total_moved;
+
+            #[rustfmt::skip]
+            self.record(table, moved_from, source, Some(*kind), Pass::Boundary, -(share as i64));
+            #[rustfmt::skip]
+            self.record(table, kept_on, source, Some(*kind), Pass::Boundary, -((count - share) as i64));
+        }
+    }
+}
+
+/// Applies `rules` to `corpus` like [`RuleSet::adapt`], recording every
+/// transfer into or out of `ngram` in `table`.
+#[instrument(level = "debug", skip(corpus, rules))]
+pub fn explain<U: Adapt>(
+    corpus: &mut U,
+    rules: &RuleSet,
+    resolution: Resolution,
+    table: Table,
+    ngram: &[char],
+) -> Result<Explanation, ChainError> {
+    let before = count(corpus, table, ngram);
+    let mut tracer = Tracer {
+        table,
+        ngram: ngram.to_vec(),
+        rule: 0,
+        round: 1,
+        contributions: Vec::new(),
+    };
+    let mut hooks = Hooks {
+        token: None,
+        tracer: Some(&mut tracer),
+    };
+    adapt_uncancellable(corpus, rules, resolution, &mut hooks)?;
+
+    Ok(Explanation {
+        table,
+        ngram: ngram.to_vec(),
+        before,
+        after: count(corpus, table, ngram),
+        contributions: tracer.contributions,
+    })
+}
+
+fn count<U: CorpusExt>(corpus: &U, table: Table, ngram: &[char]) -> u64 {
+    assert_eq!(
+        table.order(),
+        ngram.len(),
+        "Wrong length for {}",
+        table.name()
+    );
+    let idx = corpus.corpus_ngram(ngram);
+    corpus.count_at(table, idx).into()
+}
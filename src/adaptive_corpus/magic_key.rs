@@ -0,0 +1,119 @@
+// Copyright 2025 antlers <antlers@illucid.net>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Layouts with more than one magic key, each with its own rules.
+//!
+//! A [`MagicKey`] owns a placeholder (eg. `†` for the left hand's key, `‡` for
+//! the right's) and the rules that type it. Every key's rules are applied as
+//! one [`RuleSet`], so a rule of one key can read the other's placeholder,
+//! and [`adapt_keys`] reports how much of the corpus each key ended up typing.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::fs;
+//! use kc::Corpus;
+//! use km_adaptive_corpus::{MagicKey, Resolution, adapt_keys};
+//!
+//! let b = fs::read("./corpora/shai-iweb.corpus").unwrap();
+//! let mut corpus: Corpus = rmp_serde::from_slice(&b).unwrap();
+//! let keys = [
+//!     MagicKey::new("left", '†').with_rule('h', 'e'),
+//!     MagicKey::new("right", '‡').with_rule('e', 'r'),
+//! ];
+//! for report in adapt_keys(&mut corpus, &keys, Resolution::Ordered).unwrap() {
+//!     println!("{}: {}", report.name, report.absorbed);
+//! }
+//! ```
+
+use crate::adaptive_corpus::chain::{ChainError, Resolution, RuleSet};
+use crate::adaptive_corpus::{Adapt, Rule};
+
+use serde::{Deserialize, Serialize};
+
+use tracing::instrument;
+
+/// A magic key, typing `placeholder` in place of each of its rules' outputs.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MagicKey {
+    pub name: String,
+    pub placeholder: char,
+    pub rules: Vec<Rule>,
+}
+
+/// How much of the corpus a [`MagicKey`] absorbed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyReport {
+    pub name: String,
+    pub placeholder: char,
+    /// Count of the placeholder once every key has been applied.
+    pub absorbed: u64,
+    /// Placeholders written by each rule, in the key's order.
+    pub rules: Vec<(Rule, u64)>,
+}
+
+impl MagicKey {
+    pub fn new(name: &str, placeholder: char) -> Self {
+        Self {
+            name: name.to_string(),
+            placeholder,
+            rules: Vec::new(),
+        }
+    }
+
+    /// Pressing the key after `trigger` types `output`, eg. `h† -> he`.
+    pub fn with_rule(self, trigger: char, output: char) -> Self {
+        let rule = Rule::new([trigger, output], [trigger, self.placeholder]);
+        self.with(rule)
+    }
+
+    /// Adds any rule, panics unless it writes the key's placeholder.
+    pub fn with(mut self, rule: Rule) -> Self {
+        assert!(
+            rule.new.contains(&self.placeholder),
+            "Rule for {} doesn't type {}",
+            self.name,
+            self.placeholder
+        );
+        self.rules.push(rule);
+        self
+    }
+}
+
+/// Applies every key's rules together, see [`RuleSet::adapt`].
+#[instrument(level = "debug", skip(corpus))]
+pub fn adapt_keys<U: Adapt>(
+    corpus: &mut U,
+    keys: &[MagicKey],
+    resolution: Resolution,
+) -> Result<Vec<KeyReport>, ChainError> {
+    let owners: Vec<usize> = keys
+        .iter()
+        .enumerate()
+        .flat_map(|(k, key)| key.rules.iter().map(move |_| k))
+        .collect();
+    let set = RuleSet::new(keys.iter().flat_map(|key| key.rules.clone()).collect());
+
+    let mut written = vec![0u64; set.rules.len()];
+    set.run::<_, ChainError>(corpus, resolution, |corpus, i| {
+        let placeholder = [keys[owners[i]].placeholder];
+        let before: u64 = corpus.count_ngram(&placeholder).into();
+        let changed = corpus.adapt(set.rules[i]);
+        let after: u64 = corpus.count_ngram(&placeholder).into();
+        written[i] += after.saturating_sub(before);
+        Ok(changed)
+    })?;
+
+    let mut reports = Vec::with_capacity(keys.len());
+    let mut written = written.into_iter();
+    for key in keys {
+        reports.push(KeyReport {
+            name: key.name.clone(),
+            placeholder: key.placeholder,
+            absorbed: corpus.count_ngram(&[key.placeholder]).into(),
+            rules: key.rules.iter().copied().zip(written.by_ref()).collect(),
+        });
+    }
+    Ok(reports)
+}
@@ -0,0 +1,71 @@
+// Copyright 2025 antlers <antlers@illucid.net>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Adapting only the pentagrams, and deriving every lower order from them.
+//!
+//! [`Strategy::PerOrder`] adapts each order from the two above it, so orders
+//! can drift apart. [`Strategy::Marginal`] only adapts the pentagrams and
+//! moves each pentagram's change onto the ngrams it contains, so every order
+//! agrees with the pentagrams.
+//!
+//! Lower orders are taken from the middle of each pentagram, eg. chars from
+//! its third char, so every substitution that changes them is inside the
+//! pentagram, and is adapted by the interior pass alone. Quadgrams are its
+//! last four chars: a substitution across the pentagram's right edge only
+//! changes them if it changes its first char, which magic-key rules like
+//! `he -> h†` never do. Other rules' quadgrams would miss those substitutions,
+//! so they're adapted per order instead, with a warning.
+//!
+//! Only the change is moved, counts the pentagrams can't see are kept. Those
+//! are ngrams near the ends of the texts a corpus was counted from, which
+//! aren't the middle of any pentagram, and don't get adapted at all. So
+//! `Marginal` is consistent but less accurate near text boundaries, run the
+//! ignored `si_he_marginal_vs_per_order` test for numbers. It's also faster,
+//! one order is collected instead of five.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::fs;
+//! use kc::Corpus;
+//! use km_adaptive_corpus::{Adapt, Rule, Strategy};
+//!
+//! let b = fs::read("./corpora/shai-iweb.corpus").unwrap();
+//! let mut corpus: Corpus = rmp_serde::from_slice(&b).unwrap();
+//! corpus.adapt_with(Rule::new(['h', 'e'], ['h', '†']), Strategy::Marginal);
+//! ```
+
+use crate::adaptive_corpus::dry_run::Deltas;
+use crate::{CorpusExt, Table};
+
+use serde::{Deserialize, Serialize};
+
+/// How [`Adapt::adapt_with`](crate::Adapt::adapt_with) adapts each order.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Strategy {
+    /// Every order from the two above it, like [`Adapt::adapt`](crate::Adapt::adapt).
+    #[default]
+    PerOrder,
+    /// Pentagrams only, lower orders follow their change. Rules that change
+    /// their first char fall back to `PerOrder`.
+    Marginal,
+}
+
+/// Adds `pentagrams`' deltas to `deltas`, and the same deltas to each
+/// pentagram's middle chars, bigram, skipgram and trigram, and its last
+/// quadgram.
+pub fn marginalize<U: CorpusExt + ?Sized>(corpus: &U, pentagrams: &Deltas, deltas: &mut Deltas) {
+    for (table, idx, d) in pentagrams.iter() {
+        assert_eq!(table, Table::Pentagrams, "Only pentagrams are marginalized");
+        let pg = corpus.uncorpus_pentagram(idx);
+        let qg = [pg[1], pg[2], pg[3], pg[4]];
+        let tg = [pg[1], pg[2], pg[3]];
+        deltas.add(Table::Pentagrams, idx, d);
+        deltas.add(Table::Quadgrams, corpus.corpus_quadgram(&qg), d);
+        deltas.add(Table::Trigrams, corpus.corpus_trigram(&tg), d);
+        deltas.add(Table::Skipgrams, corpus.corpus_bigram(&[pg[1], pg[3]]), d);
+        deltas.add(Table::Bigrams, corpus.corpus_bigram(&[pg[1], pg[2]]), d);
+        deltas.add(Table::Chars, corpus.corpus_char(&[pg[2]]), d);
+    }
+}
@@ -2,39 +2,44 @@
 //
 // SPDX-License-Identifier: GPL-3.0-only
 
-use crate::CorpusExt;
+use crate::adaptive_corpus::context::interior_fraction;
 use crate::adaptive_corpus::*;
-use kc::Corpus;
+use crate::count::Count;
+use crate::{CorpusExt, Table};
 
 impl GetCount<[char; 2], [char; 1]> for ExpansionStruct<[char; 2], [char; 1]> {
     /// Count bigrams.
-    fn get_count<U: CorpusExt>(&self, corpus: &mut U) -> u32 {
-        let idx = corpus.corpus_bigram(&self.old);
-        corpus.get_bigrams()[idx]
+    fn get_count<U: CorpusExt>(&self, corpus: &U) -> U::Count {
+        corpus.count_ngram(&self.old)
     }
 }
 
 impl GetCount<[char; 3], [char; 1]> for ExpansionStruct<[char; 3], [char; 1]> {
     /// Count trigrams.
-    fn get_count<U: CorpusExt>(&self, corpus: &mut U) -> u32 {
-        let idx = corpus.corpus_trigram(&self.old);
-        corpus.get_trigrams()[idx]
+    fn get_count<U: CorpusExt>(&self, corpus: &U) -> U::Count {
+        corpus.count_ngram(&self.old)
     }
 }
 
 impl<U: CorpusExt> AdaptiveCorpusBase<[char; 1]> for U {
-    fn adapt_boundary_ngram<O>(
-        &mut self,
+    fn collect_boundary_ngram<O>(
+        &self,
+        rule: &Rule,
         exp: &mut Option<ExpansionStruct<O, [char; 1]>>,
-        bcount: u32,
+        bcount: u64,
+        deltas: &mut Deltas,
+        hooks: &mut Hooks<'_>,
     ) where
         ExpansionStruct<O, [char; 1]>: GetCount<O, [char; 1]>,
+        O: AsRef<[char]>,
     {
         if let Some(exp) = exp {
-            exp.set_count(exp.get_count(self) - bcount);
+            let fraction = exp.match_fraction(self, rule);
+            exp.set_count(exp.get_count(self).into() - bcount, rule, fraction);
 
             let idx = self.corpus_char(&exp.new);
-            self.get_chars()[idx] += exp.read_count();
+            deltas.add(Table::Chars, idx, exp.read_moved().delta());
+            exp.record(Table::Chars, hooks);
         }
     }
 }
@@ -49,16 +54,17 @@ impl Expand<[char; 1], [char; 2], [char; 3]> for [char; 1] {
 
         // If the char starts with the old char suffix, left
         if self[0] == old[1] {
-            left = Some(ExpansionStruct::new([old[0], self[0]], [new[1]]));
+            left = Some(ExpansionStruct::new(ExpansionKind::Left, [old[0], self[0]], [new[1]]));
         }
 
         // If the char ends with the old char prefix, right
         if self[0] == old[0] {
-            right = Some(ExpansionStruct::new([self[0], old[1]], [new[0]]));
+            right = Some(ExpansionStruct::new(ExpansionKind::Right, [self[0], old[1]], [new[0]]));
 
             // If both, both
             if let Some(ref left) = left {
                 both = Some(ExpansionStruct::new(
+                    ExpansionKind::Both,
                     [left.old[0], left.old[1], old[1]],
                     [new[0]],
                 ));
@@ -72,40 +78,66 @@ impl Expand<[char; 1], [char; 2], [char; 3]> for [char; 1] {
 /// Methods for adapting char frequencies to reflect char substitutions.
 ///
 /// # Debugging
+/// - [`explain`](crate::explain) lists the transfers into and out of one ngram
 /// - See commented code in module `test::si_compare_all_chars`
 /// ```ignore
 /// if tg == &['†', 'a', 'h'] { ... }
 /// ```
-impl AdaptiveCorpus<[char; 1]> for Corpus {
-    fn adapt_ngrams(&mut self, old: [char; 2], new: [char; 2]) {
-        // self.adapt_boundary_ngrams(old, new);
-        <Corpus as AdaptiveCorpus<[char; 1]>>::adapt_boundary_ngrams(self, old, new);
+impl<U: CorpusExt> AdaptiveCorpus<[char; 1]> for U {
+    fn collect_ngrams(
+        &self,
+        rule: Rule,
+        deltas: &mut Deltas,
+        hooks: &mut Hooks<'_>,
+    ) -> Result<(), Cancelled> {
+        // self.collect_boundary_ngrams(rule, deltas, hooks)
+        <Self as AdaptiveCorpus<[char; 1]>>::collect_boundary_ngrams(self, rule, deltas, hooks)
     }
 
-    fn adapt_boundary_ngrams(&mut self, old: [char; 2], new: [char; 2]) {
-        let num_chars = self.get_chars().len();
-        for i in 0..num_chars {
+    fn collect_boundary_ngrams(
+        &self,
+        rule: Rule,
+        deltas: &mut Deltas,
+        hooks: &mut Hooks<'_>,
+    ) -> Result<(), Cancelled> {
+        let Rule { old, new, .. } = rule;
+        for i in self.nonzero(Table::Chars) {
+            hooks.check()?;
             let c = self.uncorpus_unigram(i);
             let mut exps = [c].expand(old, new);
 
-            self.adapt_boundary_ngram(&mut exps.both, 0);
+            self.collect_boundary_ngram(&rule, &mut exps.both, 0, deltas, hooks);
             let bcount = exps.sum(&[ExpansionKind::Both]);
-            self.adapt_boundary_ngram(&mut exps.left, bcount);
-            self.adapt_boundary_ngram(&mut exps.right, bcount);
+            self.collect_boundary_ngram(&rule, &mut exps.left, bcount, deltas, hooks);
+            self.collect_boundary_ngram(&rule, &mut exps.right, bcount, deltas, hooks);
 
-            let sum = exps.sum(&[ExpansionKind::Left, ExpansionKind::Right, ExpansionKind::Both]);
+            let sum =
+                exps.sum_moved(&[ExpansionKind::Left, ExpansionKind::Right, ExpansionKind::Both]);
 
-            self.chars[i] -= sum;
+            deltas.add(Table::Chars, i, -sum.delta());
+            if hooks.tracing() {
+                hooks.record_boundary_sum(Table::Chars, &[c], &[c], 1.0, &exps.moved());
+            }
         }
+        Ok(())
     }
 
-    fn adapt_interior_ngrams(&mut self, old: [char; 2], new: [char; 2]) {}
-    fn adapt_interior_ngram(
-        &mut self,
+    fn collect_interior_ngrams(
+        &self,
+        rule: Rule,
+        deltas: &mut Deltas,
+        hooks: &mut Hooks<'_>,
+    ) -> Result<(), Cancelled> {
+        Ok(())
+    }
+    fn collect_interior_ngram(
+        &self,
+        rule: &Rule,
         old_idx: usize,
         old_ng: &[char],
         new_ng: &[char],
-        acc: &mut Vec<i32>,
+        deltas: &mut Deltas,
+        hooks: &mut Hooks<'_>,
     ) {
     }
 }
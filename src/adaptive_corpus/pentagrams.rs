@@ -2,15 +2,15 @@
 //
 // SPDX-License-Identifier: GPL-3.0-only
 
-use crate::CorpusExt;
+use crate::adaptive_corpus::context::interior_fraction;
 use crate::adaptive_corpus::*;
-use kc::Corpus;
-
+use crate::count::Count;
+use crate::{CorpusExt, Table};
 use tracing::instrument;
 
 impl GetCount<[char; 6], [char; 5]> for ExpansionStruct<[char; 6], [char; 5]> {
     /// "Count" hexagrams.
-    fn get_count<U: CorpusExt>(&self, corpus: &mut U) -> u32 {
+    fn get_count<U: CorpusExt>(&self, corpus: &U) -> U::Count {
         #[cfg(feature = "synth-large-ngrams")]
         {
             let prefix = &[
@@ -27,11 +27,7 @@ impl GetCount<[char; 6], [char; 5]> for ExpansionStruct<[char; 6], [char; 5]> {
                 self.old[4],
                 self.old[5],
             ];
-            let prefix_idx = corpus.corpus_pentagram(prefix);
-            let suffix_idx = corpus.corpus_pentagram(suffix);
-            let pgs = corpus.get_pentagrams();
-
-            std::cmp::min(pgs[prefix_idx], pgs[suffix_idx])
+            std::cmp::min(corpus.count_ngram(prefix), corpus.count_ngram(suffix))
         }
 
         #[cfg(not(feature = "synth-large-ngrams"))]
@@ -41,7 +37,7 @@ impl GetCount<[char; 6], [char; 5]> for ExpansionStruct<[char; 6], [char; 5]> {
 
 impl GetCount<[char; 7], [char; 5]> for ExpansionStruct<[char; 7], [char; 5]> {
     /// "Count" septegrams.
-    fn get_count<U: CorpusExt>(&self, corpus: &mut U) -> u32 {
+    fn get_count<U: CorpusExt>(&self, corpus: &U) -> U::Count {
         #[cfg(feature = "synth-large-ngrams")]
         {
             let prefix = &[
@@ -58,11 +54,7 @@ impl GetCount<[char; 7], [char; 5]> for ExpansionStruct<[char; 7], [char; 5]> {
                 self.old[5],
                 self.old[6],
             ];
-            let prefix_idx = corpus.corpus_pentagram(prefix);
-            let suffix_idx = corpus.corpus_pentagram(suffix);
-            let sgs = corpus.get_pentagrams();
-
-            std::cmp::min(sgs[prefix_idx], sgs[suffix_idx])
+            std::cmp::min(corpus.count_ngram(prefix), corpus.count_ngram(suffix))
         }
 
         #[cfg(not(feature = "synth-large-ngrams"))]
@@ -71,19 +63,25 @@ impl GetCount<[char; 7], [char; 5]> for ExpansionStruct<[char; 7], [char; 5]> {
 }
 
 impl<U: CorpusExt> AdaptiveCorpusBase<[char; 5]> for U {
-    fn adapt_boundary_ngram<O>(
-        &mut self,
+    fn collect_boundary_ngram<O>(
+        &self,
+        rule: &Rule,
         exp: &mut Option<ExpansionStruct<O, [char; 5]>>,
-        bcount: u32,
+        bcount: u64,
+        deltas: &mut Deltas,
+        hooks: &mut Hooks<'_>,
     ) where
         ExpansionStruct<O, [char; 5]>: GetCount<O, [char; 5]>,
+        O: AsRef<[char]>,
     {
         // XXX: if let Some(exp) = exp && exp.old.len() < 6 {
         if let Some(exp) = exp {
-            exp.set_count(exp.get_count(self) - bcount);
+            let fraction = exp.match_fraction(self, rule);
+            exp.set_count(exp.get_count(self).into() - bcount, rule, fraction);
 
             let idx = self.corpus_pentagram(&exp.new);
-            self.get_pentagrams()[idx] += exp.read_count();
+            deltas.add(Table::Pentagrams, idx, exp.read_moved().delta());
+            exp.record(Table::Pentagrams, hooks);
         }
     }
 }
@@ -123,6 +121,7 @@ impl Expand<[char; 5], [char; 6], [char; 7]> for [char; 5] {
         // If the pentagram starts with the old bigram suffix, left
         if pg[0] == old[1] {
             left = Some(ExpansionStruct::new(
+                ExpansionKind::Left,
                 [old[0], self[0], self[1], self[2], self[3], self[4]],
                 [new[1], pg[1], pg[2], pg[3], pg[4]],
             ));
@@ -131,6 +130,7 @@ impl Expand<[char; 5], [char; 6], [char; 7]> for [char; 5] {
         // If the pentagram ends with the old bigram prefix, right
         if pg[4] == old[0] {
             right = Some(ExpansionStruct::new(
+                ExpansionKind::Right,
                 [self[0], self[1], self[2], self[3], self[4], old[1]],
                 [pg[0], pg[1], pg[2], pg[3], new[0]],
             ));
@@ -138,6 +138,7 @@ impl Expand<[char; 5], [char; 6], [char; 7]> for [char; 5] {
             // If both, both
             if let Some(ref left) = left {
                 both = Some(ExpansionStruct::new(
+                    ExpansionKind::Both,
                     [
                         left.old[0],
                         left.old[1],
@@ -157,40 +158,54 @@ impl Expand<[char; 5], [char; 6], [char; 7]> for [char; 5] {
 }
 
 /// Methods for adapting pentagram frequencies to reflect bigram substitutions.
-impl AdaptiveCorpus<[char; 5]> for Corpus {
-    #[instrument(level = "debug", skip(self))]
-    fn adapt_ngrams(&mut self, old: [char; 2], new: [char; 2]) {
-        // self.adapt_interior_ngrams(old, new);
-        <Corpus as AdaptiveCorpus<[char; 5]>>::adapt_interior_ngrams(self, old, new);
+impl<U: CorpusExt> AdaptiveCorpus<[char; 5]> for U {
+    #[instrument(level = "debug", skip(self, deltas, hooks))]
+    fn collect_ngrams(
+        &self,
+        rule: Rule,
+        deltas: &mut Deltas,
+        hooks: &mut Hooks<'_>,
+    ) -> Result<(), Cancelled> {
+        // self.collect_interior_ngrams(rule, deltas, hooks)
+        <Self as AdaptiveCorpus<[char; 5]>>::collect_interior_ngrams(self, rule, deltas, hooks)
     }
 
-    fn adapt_boundary_ngrams(&mut self, old: [char; 2], new: [char; 2]) {
-        let num_pentagrams = self.get_pentagrams().len();
+    fn collect_boundary_ngrams(
+        &self,
+        rule: Rule,
+        deltas: &mut Deltas,
+        hooks: &mut Hooks<'_>,
+    ) -> Result<(), Cancelled> {
+        let Rule { old, new, .. } = rule;
 
-        for mut i in 0..num_pentagrams {
+        for mut i in self.nonzero(Table::Pentagrams) {
+            hooks.check()?;
             let mut pg = self.uncorpus_pentagram(i);
             let mut exps = [pg[0], pg[1], pg[2], pg[3], pg[4]].expand(old, new);
 
             // TODO: Change method signature to unwrap the Option here
             if exps.both.is_some() {
-                self.adapt_boundary_ngram(&mut exps.both, 0)
+                self.collect_boundary_ngram(&rule, &mut exps.both, 0, deltas, hooks)
             };
             let bcount = exps.sum(&[ExpansionKind::Both]);
             if exps.left.is_some() {
-                self.adapt_boundary_ngram(&mut exps.left, bcount)
+                self.collect_boundary_ngram(&rule, &mut exps.left, bcount, deltas, hooks)
             };
             if exps.right.is_some() {
-                self.adapt_boundary_ngram(&mut exps.right, bcount)
+                self.collect_boundary_ngram(&rule, &mut exps.right, bcount, deltas, hooks)
             };
 
-            let sum = exps.sum(&[ExpansionKind::Left, ExpansionKind::Right, ExpansionKind::Both]);
+            let sum =
+                exps.sum_moved(&[ExpansionKind::Left, ExpansionKind::Right, ExpansionKind::Both]);
 
+            let (old_i, old_pg) = (i, pg.clone());
             if pg[0] == old[0] && pg[1] == old[1] && pg[2] == old[0] && pg[3] == old[1] {
                 // hehe*
                 pg = [new[0], new[1], new[0], new[1], pg[4]].to_vec();
                 i = self.corpus_pentagram(&[pg[0], pg[1], pg[2], pg[3], pg[4]]);
             } else if pg[1] == old[0] && pg[2] == old[1] && pg[3] == old[0] && pg[4] == old[1] {
                 // *hehe
+                pg = [pg[0], new[0], new[1], new[0], new[1]].to_vec();
                 i = self.corpus_pentagram(&[pg[0], pg[1], pg[2], pg[3], pg[4]]);
             } else if pg[0] == old[0] && pg[1] == old[1] && pg[3] == old[0] && pg[4] == old[1] {
                 // he*he
@@ -214,70 +229,84 @@ impl AdaptiveCorpus<[char; 5]> for Corpus {
                 i = self.corpus_pentagram(&[pg[0], pg[1], pg[2], pg[3], pg[4]]);
             }
 
-            self.get_pentagrams()[i] -= sum;
+            // Only part of the ngram was substituted if the rule has a context
+            let fraction = interior_fraction(self, &rule, &old_pg, &pg);
+            let (moved, kept) = split_boundary_sum(sum, fraction);
+            deltas.add(Table::Pentagrams, i, -moved.delta());
+            deltas.add(Table::Pentagrams, old_i, -kept.delta());
+            if hooks.tracing() {
+                #[rustfmt::skip]
+                hooks.record_boundary_sum(Table::Pentagrams, &pg, &old_pg, fraction, &exps.moved());
+            }
         }
+        Ok(())
     }
 
-    #[instrument(level = "debug", skip(self))]
-    fn adapt_interior_ngrams(&mut self, old: [char; 2], new: [char; 2]) {
-        let num_pentagrams = self.get_pentagrams().len();
-        let mut acc = vec![0; num_pentagrams];
+    #[instrument(level = "debug", skip(self, deltas, hooks))]
+    fn collect_interior_ngrams(
+        &self,
+        rule: Rule,
+        deltas: &mut Deltas,
+        hooks: &mut Hooks<'_>,
+    ) -> Result<(), Cancelled> {
+        let Rule { old, new, .. } = rule;
 
-        for i in 0..num_pentagrams {
-            if self.pentagrams[i] == 0 {
-                continue;
-            }
+        for i in self.nonzero(Table::Pentagrams) {
+            hooks.check()?;
             let pg = self.uncorpus_pentagram(i);
 
             // XXX: Probably not correct for replacing repeats
             if pg[0] == old[0] && pg[1] == old[1] && pg[2] == old[0] && pg[3] == old[1] {
                 // hehe*
                 #[rustfmt::skip]
-                <Corpus as AdaptiveCorpus<[char; 5]>>::adapt_interior_ngram(self, i, &pg[..], &[new[0], new[1], new[0], new[1], pg[4]], &mut acc);
+                <Self as AdaptiveCorpus<[char; 5]>>::collect_interior_ngram(self, &rule, i, &pg[..], &[new[0], new[1], new[0], new[1], pg[4]], deltas, hooks);
             } else if pg[1] == old[0] && pg[2] == old[1] && pg[3] == old[0] && pg[4] == old[1] {
                 // *hehe
                 #[rustfmt::skip]
-                <Corpus as AdaptiveCorpus<[char; 5]>>::adapt_interior_ngram(self, i, &pg[..], &[pg[0], new[0], new[1], new[0], new[1]], &mut acc);
+                <Self as AdaptiveCorpus<[char; 5]>>::collect_interior_ngram(self, &rule, i, &pg[..], &[pg[0], new[0], new[1], new[0], new[1]], deltas, hooks);
             } else if pg[0] == old[0] && pg[1] == old[1] && pg[3] == old[0] && pg[4] == old[1] {
                 // he*he
                 #[rustfmt::skip]
-                <Corpus as AdaptiveCorpus<[char; 5]>>::adapt_interior_ngram(self, i, &pg[..], &[new[0], new[1], pg[2], new[0], new[1]], &mut acc);
+                <Self as AdaptiveCorpus<[char; 5]>>::collect_interior_ngram(self, &rule, i, &pg[..], &[new[0], new[1], pg[2], new[0], new[1]], deltas, hooks);
             } else if pg[0] == old[0] && pg[1] == old[1] {
                 // he***
                 #[rustfmt::skip]
-                <Corpus as AdaptiveCorpus<[char; 5]>>::adapt_interior_ngram(self, i, &pg[..], &[new[0], new[1], pg[2], pg[3], pg[4]], &mut acc);
+                <Self as AdaptiveCorpus<[char; 5]>>::collect_interior_ngram(self, &rule, i, &pg[..], &[new[0], new[1], pg[2], pg[3], pg[4]], deltas, hooks);
             } else if pg[1] == old[0] && pg[2] == old[1] {
                 // *he**
                 #[rustfmt::skip]
-                <Corpus as AdaptiveCorpus<[char; 5]>>::adapt_interior_ngram(self, i, &pg[..], &[pg[0], new[0], new[1], pg[3], pg[4]], &mut acc);
+                <Self as AdaptiveCorpus<[char; 5]>>::collect_interior_ngram(self, &rule, i, &pg[..], &[pg[0], new[0], new[1], pg[3], pg[4]], deltas, hooks);
             } else if pg[2] == old[0] && pg[3] == old[1] {
                 // **he*
                 #[rustfmt::skip]
-                <Corpus as AdaptiveCorpus<[char; 5]>>::adapt_interior_ngram(self, i, &pg[..], &[pg[0], pg[1], new[0], new[1], pg[4]], &mut acc);
+                <Self as AdaptiveCorpus<[char; 5]>>::collect_interior_ngram(self, &rule, i, &pg[..], &[pg[0], pg[1], new[0], new[1], pg[4]], deltas, hooks);
             } else if pg[3] == old[0] && pg[4] == old[1] {
                 // ***he
                 #[rustfmt::skip]
-                <Corpus as AdaptiveCorpus<[char; 5]>>::adapt_interior_ngram(self, i, &pg[..], &[pg[0], pg[1], pg[2], new[0], new[1]], &mut acc);
+                <Self as AdaptiveCorpus<[char; 5]>>::collect_interior_ngram(self, &rule, i, &pg[..], &[pg[0], pg[1], pg[2], new[0], new[1]], deltas, hooks);
             }
         }
-
-        for (a, b) in self.get_pentagrams().iter_mut().zip(&acc) {
-            *a = a.checked_add_signed(*b).expect("Overflow!");
-        }
+        Ok(())
     }
 
-    fn adapt_interior_ngram(
-        &mut self,
+    fn collect_interior_ngram(
+        &self,
+        rule: &Rule,
         old_idx: usize,
         old_ng: &[char],
         new_ng: &[char],
-        acc: &mut Vec<i32>,
+        deltas: &mut Deltas,
+        hooks: &mut Hooks<'_>,
     ) {
-        let freq = self.get_pentagrams()[old_idx];
-        acc[old_idx] = acc[old_idx].checked_sub_unsigned(freq).unwrap();
+        let fraction = interior_fraction(self, rule, old_ng, new_ng);
+        let freq = U::Count::narrow(
+            rule.scale(self.count_at(Table::Pentagrams, old_idx).into(), fraction),
+        );
+        deltas.add(Table::Pentagrams, old_idx, -freq.delta());
 
         let new_idx =
             self.corpus_pentagram(&[new_ng[0], new_ng[1], new_ng[2], new_ng[3], new_ng[4]]);
-        acc[new_idx] = acc[new_idx].checked_add_unsigned(freq).unwrap();
+        deltas.add(Table::Pentagrams, new_idx, freq.delta());
+        hooks.record_interior(Table::Pentagrams, old_ng, new_ng, freq.delta());
     }
 }
@@ -0,0 +1,236 @@
+// Copyright 2025 antlers <antlers@illucid.net>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Reporting progress of, and cancelling, long adaptations.
+//!
+//! [`adapt_cancellable`] adapts like [`RuleSet::adapt`], calling back after
+//! each order of each rule. The [`CancelToken`] is passed down to every
+//! order's loops in [`Hooks`], which stop at the next ngram once it's
+//! cancelled, and a cancelled run puts the corpus back the way it was.
+//!
+//! Each rule's orders are collected before any are applied (see
+//! [`dry_run`](crate::adaptive_corpus::dry_run)), and applying a rule records
+//! how to undo it, saturated counts included.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::{fs, sync::mpsc, thread};
+//! use kc::Corpus;
+//! use km_adaptive_corpus::{CancelToken, Resolution, Rule, RuleSet, adapt_cancellable};
+//!
+//! let b = fs::read("./corpora/shai-iweb.corpus").unwrap();
+//! let mut corpus: Corpus = rmp_serde::from_slice(&b).unwrap();
+//! let rules = RuleSet::new(vec![Rule::new(['h', 'e'], ['h', '†'])]);
+//!
+//! let token = CancelToken::new();
+//! let (tx, rx) = mpsc::channel();
+//! let canceller = token.clone();
+//! thread::spawn(move || {
+//!     for progress in rx {
+//!         println!("{:.0}%", progress.fraction() * 100.0);
+//!         if user_changed_a_rule() {
+//!             canceller.cancel();
+//!         }
+//!     }
+//! });
+//! let result = adapt_cancellable(&mut corpus, &rules, Resolution::Ordered, &token, |p| {
+//!     let _ = tx.send(p);
+//! });
+//! # fn user_changed_a_rule() -> bool { true }
+//! ```
+
+use crate::adaptive_corpus::chain::{ChainError, CycleError, Resolution, RuleSet};
+use crate::adaptive_corpus::dry_run::Deltas;
+use crate::adaptive_corpus::{Adapt, Cancelled, Hooks, ORDERS, Rule};
+
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use tracing::instrument;
+
+/// Cancels an [`adapt_cancellable`] run from any thread, clones share a flag.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// An order of a rule that's been collected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    /// Starting at 1, see [`Resolution::FixedPoint`].
+    pub pass: usize,
+    /// Index of the rule in the [`RuleSet`].
+    pub rule: usize,
+    /// How many of this pass's rules are done, this one excluded.
+    pub rules_done: usize,
+    pub rules: usize,
+    pub order: usize,
+}
+
+impl Progress {
+    /// How much of this pass is done, from 0 to 1.
+    pub fn fraction(&self) -> f64 {
+        let orders = ORDERS.len();
+        let done = self.rules_done * orders + self.order;
+        done as f64 / (self.rules * orders) as f64
+    }
+}
+
+/// Why an [`adapt_cancellable`] run stopped early.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AdaptError {
+    Cycle(CycleError),
+    /// See [`ChainError::NoFixedPoint`].
+    NoFixedPoint {
+        max_passes: usize,
+    },
+    /// See [`ChainError::PartialRate`].
+    PartialRate {
+        rule: Rule,
+    },
+    /// The corpus was left unchanged.
+    Cancelled,
+}
+
+impl fmt::Display for AdaptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AdaptError::Cycle(e) => write!(f, "{e}"),
+            AdaptError::NoFixedPoint { max_passes } => {
+                write!(
+                    f,
+                    "rules still changed the corpus after {max_passes} passes"
+                )
+            }
+            AdaptError::PartialRate { rule } => {
+                write!(f, "{}", ChainError::PartialRate { rule: *rule })
+            }
+            AdaptError::Cancelled => write!(f, "adaptation was cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for AdaptError {}
+
+impl From<ChainError> for AdaptError {
+    fn from(e: ChainError) -> Self {
+        match e {
+            ChainError::Cycle(e) => AdaptError::Cycle(e),
+            ChainError::NoFixedPoint { max_passes } => AdaptError::NoFixedPoint { max_passes },
+            ChainError::PartialRate { rule } => AdaptError::PartialRate { rule },
+        }
+    }
+}
+
+impl From<Cancelled> for AdaptError {
+    fn from(_: Cancelled) -> Self {
+        AdaptError::Cancelled
+    }
+}
+
+/// Applies `rules` to `corpus` like [`RuleSet::adapt`], calling `progress`
+/// after each order of each rule.
+///
+/// Returns the number of passes taken. If `token` is cancelled before the
+/// last rule is applied, `corpus` is restored and [`AdaptError::Cancelled`]
+/// returned.
+#[instrument(level = "debug", skip_all)]
+pub fn adapt_cancellable<U: Adapt>(
+    corpus: &mut U,
+    rules: &RuleSet,
+    resolution: Resolution,
+    token: &CancelToken,
+    progress: impl FnMut(Progress),
+) -> Result<usize, AdaptError> {
+    let mut hooks = Hooks::with_token(token);
+    adapt_undoable(corpus, rules, resolution, &mut hooks, progress).map(|(passes, _)| passes)
+}
+
+/// Like [`adapt_cancellable`], but also returns the deltas that undo the run,
+/// in the order to apply them.
+pub(crate) fn adapt_undoable<U: Adapt>(
+    corpus: &mut U,
+    rules: &RuleSet,
+    resolution: Resolution,
+    hooks: &mut Hooks<'_>,
+    mut progress: impl FnMut(Progress),
+) -> Result<(usize, Vec<Deltas>), AdaptError> {
+    let n = rules.rules.len();
+    let mut undo: Vec<Deltas> = Vec::new();
+    let mut step = 0;
+    let result: Result<usize, AdaptError> = rules.run(corpus, resolution, |corpus, i| {
+        let (pass, rules_done) = (step / n + 1, step % n);
+        step += 1;
+        hooks.check()?;
+        hooks.trace_rule(i, pass);
+
+        let rule = rules.rules[i];
+        let mut collected = Vec::with_capacity(ORDERS.len());
+        for order in ORDERS {
+            let mut deltas = Deltas::default();
+            corpus.collect_order(order, rule, &mut deltas, hooks)?;
+            progress(Progress {
+                pass,
+                rule: i,
+                rules_done,
+                rules: n,
+                order,
+            });
+            collected.push(deltas);
+        }
+        let mut changed = false;
+        for deltas in collected {
+            let deltas = deltas.apply_undoable(corpus);
+            changed |= !deltas.is_empty();
+            undo.push(deltas);
+        }
+        Ok(changed)
+    });
+
+    match result {
+        Ok(passes) => {
+            undo.reverse();
+            Ok((passes, undo))
+        }
+        Err(AdaptError::Cancelled) => {
+            for deltas in undo.iter().rev() {
+                deltas.apply(corpus);
+            }
+            Err(AdaptError::Cancelled)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// [`adapt_undoable`] for `hooks` without a token, which can't be cancelled.
+///
+/// [`RuleSet::adapt`], with or without a cache, and
+/// [`explain`](crate::explain) all adapt through here.
+pub(crate) fn adapt_uncancellable<U: Adapt>(
+    corpus: &mut U,
+    rules: &RuleSet,
+    resolution: Resolution,
+    hooks: &mut Hooks<'_>,
+) -> Result<(usize, Vec<Deltas>), ChainError> {
+    adapt_undoable(corpus, rules, resolution, hooks, |_| {}).map_err(|e| match e {
+        AdaptError::Cycle(e) => ChainError::Cycle(e),
+        AdaptError::NoFixedPoint { max_passes } => ChainError::NoFixedPoint { max_passes },
+        AdaptError::PartialRate { rule } => ChainError::PartialRate { rule },
+        AdaptError::Cancelled => unreachable!("Nothing to cancel!"),
+    })
+}
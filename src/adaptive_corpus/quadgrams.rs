@@ -2,23 +2,25 @@
 //
 // SPDX-License-Identifier: GPL-3.0-only
 
-use crate::CorpusExt;
+use crate::adaptive_corpus::context::interior_fraction;
 use crate::adaptive_corpus::*;
-use kc::Corpus;
+use crate::count::Count;
+use crate::{CorpusExt, Table};
+
+use std::collections::BTreeSet;
 
 use tracing::instrument;
 
 impl GetCount<[char; 5], [char; 4]> for ExpansionStruct<[char; 5], [char; 4]> {
     /// Count pentagrams.
-    fn get_count<U: CorpusExt>(&self, corpus: &mut U) -> u32 {
-        let idx = corpus.corpus_pentagram(&self.old);
-        corpus.get_pentagrams()[idx]
+    fn get_count<U: CorpusExt>(&self, corpus: &U) -> U::Count {
+        corpus.count_ngram(&self.old)
     }
 }
 
 impl GetCount<[char; 6], [char; 4]> for ExpansionStruct<[char; 6], [char; 4]> {
     /// "Count" hexagrams.
-    fn get_count<U: CorpusExt>(&self, corpus: &mut U) -> u32 {
+    fn get_count<U: CorpusExt>(&self, corpus: &U) -> U::Count {
         #[cfg(feature = "synth-large-ngrams")]
         {
             let prefix = &[
@@ -35,11 +37,7 @@ impl GetCount<[char; 6], [char; 4]> for ExpansionStruct<[char; 6], [char; 4]> {
                 self.old[4],
                 self.old[5],
             ];
-            let prefix_idx = corpus.corpus_pentagram(prefix);
-            let suffix_idx = corpus.corpus_pentagram(suffix);
-            let pgs = corpus.get_pentagrams();
-
-            std::cmp::min(pgs[prefix_idx], pgs[suffix_idx])
+            std::cmp::min(corpus.count_ngram(prefix), corpus.count_ngram(suffix))
         }
 
         #[cfg(not(feature = "synth-large-ngrams"))]
@@ -48,20 +46,26 @@ impl GetCount<[char; 6], [char; 4]> for ExpansionStruct<[char; 6], [char; 4]> {
 }
 
 impl<U: CorpusExt> AdaptiveCorpusBase<[char; 4]> for U {
-    fn adapt_boundary_ngram<O>(
-        &mut self,
+    fn collect_boundary_ngram<O>(
+        &self,
+        rule: &Rule,
         exp: &mut Option<ExpansionStruct<O, [char; 4]>>,
-        bcount: u32,
+        bcount: u64,
+        deltas: &mut Deltas,
+        hooks: &mut Hooks<'_>,
     ) where
         ExpansionStruct<O, [char; 4]>: GetCount<O, [char; 4]>,
         O: std::fmt::Debug,
+        O: AsRef<[char]>,
     {
         // XXX: if let Some(exp) = exp && exp.old.len() < 6 {
         if let Some(exp) = exp {
-            exp.set_count(exp.get_count(self) - bcount);
+            let fraction = exp.match_fraction(self, rule);
+            exp.set_count(exp.get_count(self).into() - bcount, rule, fraction);
 
             let idx = self.corpus_quadgram(&exp.new);
-            self.get_quadgrams()[idx] += exp.read_count();
+            deltas.add(Table::Quadgrams, idx, exp.read_moved().delta());
+            exp.record(Table::Quadgrams, hooks);
         }
     }
 }
@@ -92,6 +96,7 @@ impl Expand<[char; 4], [char; 5], [char; 6]> for [char; 4] {
         // If the quadgram starts with the old bigram suffix, left
         if self[0] == old[1] {
             left = Some(ExpansionStruct::new(
+                ExpansionKind::Left,
                 [old[0], qg[0], qg[1], qg[2], qg[3]],
                 [new[1], qg[1], qg[2], qg[3]],
             ));
@@ -100,6 +105,7 @@ impl Expand<[char; 4], [char; 5], [char; 6]> for [char; 4] {
         // If the quadgram ends with the old bigram prefix, right
         if self[3] == old[0] {
             right = Some(ExpansionStruct::new(
+                ExpansionKind::Right,
                 [qg[0], qg[1], qg[2], qg[3], old[1]],
                 [qg[0], qg[1], qg[2], new[0]],
             ));
@@ -107,6 +113,7 @@ impl Expand<[char; 4], [char; 5], [char; 6]> for [char; 4] {
             // If both, both
             if let Some(ref left) = left {
                 both = Some(ExpansionStruct::new(
+                    ExpansionKind::Both,
                     [
                         left.old[0],
                         left.old[1],
@@ -125,20 +132,47 @@ impl Expand<[char; 4], [char; 5], [char; 6]> for [char; 4] {
 }
 
 /// Methods for adapting quadgram frequencies to reflect bigram substitutions.
-impl AdaptiveCorpus<[char; 4]> for Corpus {
-    fn adapt_ngrams(&mut self, old: [char; 2], new: [char; 2]) {
-        // self.adapt_interior_ngrams(old, new);
-        <Corpus as AdaptiveCorpus<[char; 4]>>::adapt_interior_ngrams(self, old, new);
-        // self.adapt_boundary_ngrams(old, new);
-        <Corpus as AdaptiveCorpus<[char; 4]>>::adapt_boundary_ngrams(self, old, new);
+impl<U: CorpusExt> AdaptiveCorpus<[char; 4]> for U {
+    fn collect_ngrams(
+        &self,
+        rule: Rule,
+        deltas: &mut Deltas,
+        hooks: &mut Hooks<'_>,
+    ) -> Result<(), Cancelled> {
+        // self.collect_interior_ngrams(rule, deltas, hooks)?;
+        <Self as AdaptiveCorpus<[char; 4]>>::collect_interior_ngrams(self, rule, deltas, hooks)?;
+        // self.collect_boundary_ngrams(rule, deltas, hooks)
+        <Self as AdaptiveCorpus<[char; 4]>>::collect_boundary_ngrams(self, rule, deltas, hooks)
     }
 
-    #[instrument(level = "debug", skip(self))]
-    fn adapt_boundary_ngrams(&mut self, old: [char; 2], new: [char; 2]) {
-        let num_quadgrams = self.get_quadgrams().len();
+    #[instrument(level = "debug", skip(self, deltas, hooks))]
+    fn collect_boundary_ngrams(
+        &self,
+        rule: Rule,
+        deltas: &mut Deltas,
+        hooks: &mut Hooks<'_>,
+    ) -> Result<(), Cancelled> {
+        let Rule { old, new, .. } = rule;
+
+        // The interior pass hasn't been applied yet, so the quadgrams it moved
+        // counts into are visited too, and so are the ones this pass moves
+        // counts into, in the order a dense loop would visit them
+        let mut pending: BTreeSet<_> = self
+            .nonzero(Table::Quadgrams)
+            .map(|i| dense_order(self, i))
+            .collect();
+        pending.extend(
+            deltas
+                .iter()
+                .filter(|&(table, _, d)| table == Table::Quadgrams && d > 0)
+                .map(|(_, idx, _)| dense_order(self, idx)),
+        );
 
-        for mut i in 0..num_quadgrams {
-            if self.quadgrams[i] == 0 {
+        while let Some(place) = pending.pop_first() {
+            let mut i = place.1;
+            hooks.check()?;
+            let count: u64 = self.count_at(Table::Quadgrams, i).into();
+            if count.delta() + deltas.get(Table::Quadgrams, i) <= 0 {
                 continue;
             }
             let mut qg = self.uncorpus_quadgram(i);
@@ -146,18 +180,31 @@ impl AdaptiveCorpus<[char; 4]> for Corpus {
 
             // TODO: Change method signature to unwrap the Option here
             if exps.both.is_some() {
-                self.adapt_boundary_ngram(&mut exps.both, 0)
+                self.collect_boundary_ngram(&rule, &mut exps.both, 0, deltas, hooks)
             };
             let bcount = exps.sum(&[ExpansionKind::Both]);
             if exps.left.is_some() {
-                self.adapt_boundary_ngram(&mut exps.left, bcount)
+                self.collect_boundary_ngram(&rule, &mut exps.left, bcount, deltas, hooks)
             };
             if exps.right.is_some() {
-                self.adapt_boundary_ngram(&mut exps.right, bcount)
+                self.collect_boundary_ngram(&rule, &mut exps.right, bcount, deltas, hooks)
             };
 
-            let sum = exps.sum(&[ExpansionKind::Left, ExpansionKind::Right, ExpansionKind::Both]);
+            let sum =
+                exps.sum_moved(&[ExpansionKind::Left, ExpansionKind::Right, ExpansionKind::Both]);
+            let news = [
+                exps.both.as_ref().map(|exp| exp.new),
+                exps.left.as_ref().map(|exp| exp.new),
+                exps.right.as_ref().map(|exp| exp.new),
+            ];
+            let later = news
+                .into_iter()
+                .flatten()
+                .map(|ng| dense_order(self, self.corpus_quadgram(&ng)))
+                .filter(|&later| later > place);
+            pending.extend(later);
 
+            let (old_i, old_qg) = (i, qg.clone());
             if qg[0] == old[0] && qg[1] == old[1] && qg[2] == old[0] && qg[3] == old[1] {
                 // hehe
                 qg = [new[0], new[1], new[0], new[1]].to_vec();
@@ -176,54 +223,77 @@ impl AdaptiveCorpus<[char; 4]> for Corpus {
                 i = self.corpus_quadgram(&[qg[0], qg[1], qg[2], qg[3]]);
             }
 
-            self.get_quadgrams()[i] -= sum;
+            // Only part of the ngram was substituted if the rule has a context
+            let fraction = interior_fraction(self, &rule, &old_qg, &qg);
+            let (moved, kept) = split_boundary_sum(sum, fraction);
+            deltas.add(Table::Quadgrams, i, -moved.delta());
+            deltas.add(Table::Quadgrams, old_i, -kept.delta());
+            if hooks.tracing() {
+                #[rustfmt::skip]
+                hooks.record_boundary_sum(Table::Quadgrams, &qg, &old_qg, fraction, &exps.moved());
+            }
         }
+        Ok(())
     }
 
-    #[instrument(level = "debug", skip(self))]
-    fn adapt_interior_ngrams(&mut self, old: [char; 2], new: [char; 2]) {
-        let num_quadgrams = self.get_quadgrams().len();
-        let mut acc = vec![0; num_quadgrams];
+    #[instrument(level = "debug", skip(self, deltas, hooks))]
+    fn collect_interior_ngrams(
+        &self,
+        rule: Rule,
+        deltas: &mut Deltas,
+        hooks: &mut Hooks<'_>,
+    ) -> Result<(), Cancelled> {
+        let Rule { old, new, .. } = rule;
 
-        for i in 0..num_quadgrams {
+        for i in self.nonzero(Table::Quadgrams) {
+            hooks.check()?;
             let qg = self.uncorpus_quadgram(i);
 
             // XXX: Probably not correct for replacing repeats
             if qg[0] == old[0] && qg[1] == old[1] && qg[2] == old[0] && qg[3] == old[1] {
                 // hehe
                 #[rustfmt::skip]
-                <Corpus as AdaptiveCorpus<[char; 4]>>::adapt_interior_ngram(self, i, &qg[..], &[new[0], new[1], new[0], new[1]], &mut acc);
+                <Self as AdaptiveCorpus<[char; 4]>>::collect_interior_ngram(self, &rule, i, &qg[..], &[new[0], new[1], new[0], new[1]], deltas, hooks);
             } else if qg[0] == old[0] && qg[1] == old[1] {
                 // he**
                 #[rustfmt::skip]
-                <Corpus as AdaptiveCorpus<[char; 4]>>::adapt_interior_ngram(self, i, &qg[..], &[new[0], new[1], qg[2], qg[3]], &mut acc);
+                <Self as AdaptiveCorpus<[char; 4]>>::collect_interior_ngram(self, &rule, i, &qg[..], &[new[0], new[1], qg[2], qg[3]], deltas, hooks);
             } else if qg[1] == old[0] && qg[2] == old[1] {
                 // *he*
                 #[rustfmt::skip]
-                <Corpus as AdaptiveCorpus<[char; 4]>>::adapt_interior_ngram(self, i, &qg[..], &[qg[0], new[0], new[1], qg[3]], &mut acc);
+                <Self as AdaptiveCorpus<[char; 4]>>::collect_interior_ngram(self, &rule, i, &qg[..], &[qg[0], new[0], new[1], qg[3]], deltas, hooks);
             } else if qg[2] == old[0] && qg[3] == old[1] {
                 // **he
                 #[rustfmt::skip]
-                <Corpus as AdaptiveCorpus<[char; 4]>>::adapt_interior_ngram(self, i, &qg[..], &[qg[0], qg[1], new[0], new[1]], &mut acc);
+                <Self as AdaptiveCorpus<[char; 4]>>::collect_interior_ngram(self, &rule, i, &qg[..], &[qg[0], qg[1], new[0], new[1]], deltas, hooks);
             }
         }
-
-        for (a, b) in self.get_quadgrams().iter_mut().zip(&acc) {
-            *a = a.saturating_add_signed(*b);
-        }
+        Ok(())
     }
 
-    fn adapt_interior_ngram(
-        &mut self,
+    fn collect_interior_ngram(
+        &self,
+        rule: &Rule,
         old_idx: usize,
         old_ng: &[char],
         new_ng: &[char],
-        acc: &mut Vec<i32>,
+        deltas: &mut Deltas,
+        hooks: &mut Hooks<'_>,
     ) {
-        let freq = self.get_quadgrams()[old_idx];
+        let fraction = interior_fraction(self, rule, old_ng, new_ng);
+        let freq =
+            U::Count::narrow(rule.scale(self.count_at(Table::Quadgrams, old_idx).into(), fraction));
         let new_idx = self.corpus_quadgram(&[new_ng[0], new_ng[1], new_ng[2], new_ng[3]]);
 
-        acc[old_idx] = acc[old_idx].saturating_sub_unsigned(freq);
-        acc[new_idx] = acc[new_idx].saturating_add_unsigned(freq);
+        deltas.add(Table::Quadgrams, old_idx, -freq.delta());
+        deltas.add(Table::Quadgrams, new_idx, freq.delta());
+        hooks.record_interior(Table::Quadgrams, old_ng, new_ng, freq.delta());
     }
 }
+
+/// Where a dense backend's index order puts quadgram `i`: by its chars'
+/// positions in the char list, which other backends' indices needn't follow.
+fn dense_order<U: CorpusExt>(corpus: &U, i: usize) -> ([usize; 4], usize) {
+    let qg = corpus.uncorpus_quadgram(i);
+    (std::array::from_fn(|k| corpus.corpus_char(&[qg[k]])), i)
+}
@@ -2,50 +2,57 @@
 //
 // SPDX-License-Identifier: GPL-3.0-only
 
-use crate::CorpusExt;
+use crate::adaptive_corpus::context::interior_fraction;
+use crate::adaptive_corpus::explain::Pass;
 use crate::adaptive_corpus::*;
-
-use kc::Corpus;
+use crate::count::Count;
+use crate::{CorpusExt, Table};
 
 use tracing::instrument;
 
 impl GetCount<[char; 4], [char; 3]> for ExpansionStruct<[char; 4], [char; 3]> {
     /// Count quadgrams.
-    fn get_count<U: CorpusExt>(&self, corpus: &mut U) -> u32 {
-        let idx = corpus.corpus_quadgram(&self.old);
-        corpus.get_quadgrams()[idx]
+    fn get_count<U: CorpusExt>(&self, corpus: &U) -> U::Count {
+        corpus.count_ngram(&self.old)
     }
 }
 
 impl GetCount<[char; 5], [char; 3]> for ExpansionStruct<[char; 5], [char; 3]> {
     /// Count pentagrams.
-    fn get_count<U: CorpusExt>(&self, corpus: &mut U) -> u32 {
-        let idx = corpus.corpus_pentagram(&self.old);
-        corpus.get_pentagrams()[idx]
+    fn get_count<U: CorpusExt>(&self, corpus: &U) -> U::Count {
+        corpus.count_ngram(&self.old)
     }
 }
 
 impl<U: CorpusExt> AdaptiveCorpusBase<[char; 3]> for U {
-    #[instrument(level = "trace", skip(self))]
-    fn adapt_boundary_ngram<O>(
-        &mut self,
+    #[instrument(level = "trace", skip(self, deltas, hooks))]
+    fn collect_boundary_ngram<O>(
+        &self,
+        rule: &Rule,
         exp: &mut Option<ExpansionStruct<O, [char; 3]>>,
-        bcount: u32,
+        bcount: u64,
+        deltas: &mut Deltas,
+        hooks: &mut Hooks<'_>,
     ) where
         ExpansionStruct<O, [char; 3]>: GetCount<O, [char; 3]>,
         O: std::fmt::Debug,
+        O: AsRef<[char]>,
     {
         if let Some(exp) = exp {
-            exp.set_count(exp.get_count(self) - bcount);
+            let fraction = exp.match_fraction(self, rule);
+            exp.set_count(exp.get_count(self).into() - bcount, rule, fraction);
 
             let idx = self.corpus_trigram(&exp.new);
-            self.get_trigrams()[idx] += exp.read_count();
+            deltas.add(Table::Trigrams, idx, exp.read_moved().delta());
 
             // Skipgrams
             // XXX: Half-assed, assumes all corpus chars are valid.
             let new_sg = &[exp.new[0], exp.new[2]];
             let new_sg_idx = self.corpus_bigram(new_sg);
-            self.get_skipgrams()[new_sg_idx] += exp.read_count();
+            deltas.add(Table::Skipgrams, new_sg_idx, exp.read_moved().delta());
+            exp.record(Table::Trigrams, hooks);
+            #[rustfmt::skip]
+            hooks.record(Table::Skipgrams, new_sg, exp.old.as_ref(), Some(exp.kind), Pass::Boundary, exp.read_moved() as i64);
         }
     }
 }
@@ -70,6 +77,7 @@ impl Expand<[char; 3], [char; 4], [char; 5]> for [char; 3] {
         // If the trigram starts with the old bigram suffix, left
         if self[0] == old[1] {
             left = Some(ExpansionStruct::new(
+                ExpansionKind::Left,
                 [old[0], self[0], self[1], self[2]],
                 [new[1], tg[1], tg[2]],
             ));
@@ -78,6 +86,7 @@ impl Expand<[char; 3], [char; 4], [char; 5]> for [char; 3] {
         // If the trigram ends with the old bigram prefix, right
         if self[2] == old[0] {
             right = Some(ExpansionStruct::new(
+                ExpansionKind::Right,
                 [self[0], self[1], self[2], old[1]],
                 [tg[0], tg[1], new[0]],
             ));
@@ -85,6 +94,7 @@ impl Expand<[char; 3], [char; 4], [char; 5]> for [char; 3] {
             // If both, both
             if let Some(ref left) = left {
                 both = Some(ExpansionStruct::new(
+                    ExpansionKind::Both,
                     [left.old[0], left.old[1], left.old[2], left.old[3], old[1]],
                     [left.new[0], left.new[1], new[0]],
                 ));
@@ -98,40 +108,54 @@ impl Expand<[char; 3], [char; 4], [char; 5]> for [char; 3] {
 /// Methods for adapting trigram frequencies to reflect bigram substitutions.
 ///
 /// # Debugging
+/// - [`explain`](crate::explain) lists the transfers into and out of one ngram
 /// - See commented code in module `test::si_compare_all_trigrams`
 /// ```ignore
 /// if tg == &['†', 'a', 'h'] { ... }
 /// ```
-impl AdaptiveCorpus<[char; 3]> for Corpus {
-    fn adapt_ngrams(&mut self, old: [char; 2], new: [char; 2]) {
-        // self.adapt_interior_ngrams(old, new);
-        <Corpus as AdaptiveCorpus<[char; 3]>>::adapt_interior_ngrams(self, old, new);
-        // self.adapt_boundary_ngrams(old, new);
-        <Corpus as AdaptiveCorpus<[char; 3]>>::adapt_boundary_ngrams(self, old, new);
+impl<U: CorpusExt> AdaptiveCorpus<[char; 3]> for U {
+    fn collect_ngrams(
+        &self,
+        rule: Rule,
+        deltas: &mut Deltas,
+        hooks: &mut Hooks<'_>,
+    ) -> Result<(), Cancelled> {
+        // self.collect_interior_ngrams(rule, deltas, hooks)?;
+        <Self as AdaptiveCorpus<[char; 3]>>::collect_interior_ngrams(self, rule, deltas, hooks)?;
+        // self.collect_boundary_ngrams(rule, deltas, hooks)
+        <Self as AdaptiveCorpus<[char; 3]>>::collect_boundary_ngrams(self, rule, deltas, hooks)
     }
 
-    #[instrument(level = "trace", skip(self))]
-    fn adapt_boundary_ngrams(&mut self, old: [char; 2], new: [char; 2]) {
-        let num_trigrams = self.get_trigrams().len();
-
-        for mut i in 0..num_trigrams {
+    #[instrument(level = "trace", skip(self, deltas, hooks))]
+    fn collect_boundary_ngrams(
+        &self,
+        rule: Rule,
+        deltas: &mut Deltas,
+        hooks: &mut Hooks<'_>,
+    ) -> Result<(), Cancelled> {
+        let Rule { old, new, .. } = rule;
+
+        for mut i in self.nonzero(Table::Trigrams) {
+            hooks.check()?;
             let mut tg = self.uncorpus_trigram(i);
             let mut exps = [tg[0], tg[1], tg[2]].expand(old, new);
 
             // TODO: Change method signature to unwrap the Option here
             if exps.both.is_some() {
-                self.adapt_boundary_ngram(&mut exps.both, 0)
+                self.collect_boundary_ngram(&rule, &mut exps.both, 0, deltas, hooks)
             };
             let bcount = exps.sum(&[ExpansionKind::Both]);
             if exps.left.is_some() {
-                self.adapt_boundary_ngram(&mut exps.left, bcount)
+                self.collect_boundary_ngram(&rule, &mut exps.left, bcount, deltas, hooks)
             };
             if exps.right.is_some() {
-                self.adapt_boundary_ngram(&mut exps.right, bcount)
+                self.collect_boundary_ngram(&rule, &mut exps.right, bcount, deltas, hooks)
             };
 
-            let sum = exps.sum(&[ExpansionKind::Left, ExpansionKind::Right, ExpansionKind::Both]);
+            let sum =
+                exps.sum_moved(&[ExpansionKind::Left, ExpansionKind::Right, ExpansionKind::Both]);
 
+            let (old_i, old_tg) = (i, tg.clone());
             if tg[0] == old[0] && tg[1] == old[1] {
                 // he*
                 tg = [new[0], new[1], tg[2]].to_vec();
@@ -142,64 +166,86 @@ impl AdaptiveCorpus<[char; 3]> for Corpus {
                 i = self.corpus_trigram(&[tg[0], tg[1], tg[2]]);
             }
 
-            self.get_trigrams()[i] -= sum;
+            // Only part of the ngram was substituted if the rule has a context
+            let fraction = interior_fraction(self, &rule, &old_tg, &tg);
+            let (moved, kept) = split_boundary_sum(sum, fraction);
+            deltas.add(Table::Trigrams, i, -moved.delta());
+            deltas.add(Table::Trigrams, old_i, -kept.delta());
 
             // Skipgrams
             // XXX: Half-assed, assumes all corpus chars were valid.
             let sg = &[tg[0], tg[2]];
             let idx = self.corpus_bigram(sg);
-            self.skipgrams[idx] -= sum;
+            deltas.add(Table::Skipgrams, idx, -moved.delta());
+            let old_sg = &[old_tg[0], old_tg[2]];
+            let idx = self.corpus_bigram(old_sg);
+            deltas.add(Table::Skipgrams, idx, -kept.delta());
+
+            if hooks.tracing() {
+                let moved = exps.moved();
+                #[rustfmt::skip]
+                hooks.record_boundary_sum(Table::Trigrams, &tg, &old_tg, fraction, &moved);
+                #[rustfmt::skip]
+                hooks.record_boundary_sum(Table::Skipgrams, sg, old_sg, fraction, &moved);
+            }
         }
+        Ok(())
     }
 
-    #[instrument(level = "debug", skip(self))]
-    fn adapt_interior_ngrams(&mut self, old: [char; 2], new: [char; 2]) {
-        let num_trigrams = self.get_trigrams().len();
-        let mut acc = vec![0; num_trigrams];
-
-        for i in 0..num_trigrams {
-            if self.trigrams[i] == 0 {
-                continue;
-            }
+    #[instrument(level = "debug", skip(self, deltas, hooks))]
+    fn collect_interior_ngrams(
+        &self,
+        rule: Rule,
+        deltas: &mut Deltas,
+        hooks: &mut Hooks<'_>,
+    ) -> Result<(), Cancelled> {
+        let Rule { old, new, .. } = rule;
+
+        for i in self.nonzero(Table::Trigrams) {
+            hooks.check()?;
             let tg = self.uncorpus_trigram(i);
             if tg[0] == old[0] && tg[1] == old[1] {
                 // he*
-                // self.adapt_interior_ngram(i, &tg[..], &[new[0], new[1], tg[2]]);
+                // self.collect_interior_ngram(i, &tg[..], &[new[0], new[1], tg[2]]);
                 #[rustfmt::skip]
-                <Corpus as AdaptiveCorpus<[char; 3]>>::adapt_interior_ngram(self, i, &tg[..], &[new[0], new[1], tg[2]], &mut acc);
+                <Self as AdaptiveCorpus<[char; 3]>>::collect_interior_ngram(self, &rule, i, &tg[..], &[new[0], new[1], tg[2]], deltas, hooks);
             }
             if tg[1] == old[0] && tg[2] == old[1] {
                 // *he
-                // self.adapt_interior_ngram(i, &tg[..], &[tg[0], new[0], new[1]]);
+                // self.collect_interior_ngram(i, &tg[..], &[tg[0], new[0], new[1]]);
                 #[rustfmt::skip]
-                <Corpus as AdaptiveCorpus<[char; 3]>>::adapt_interior_ngram(self, i, &tg[..], &[tg[0], new[0], new[1]], &mut acc);
+                <Self as AdaptiveCorpus<[char; 3]>>::collect_interior_ngram(self, &rule, i, &tg[..], &[tg[0], new[0], new[1]], deltas, hooks);
             }
         }
-
-        for (a, b) in self.get_trigrams().iter_mut().zip(&acc) {
-            *a = a.saturating_add_signed(*b);
-        }
+        Ok(())
     }
 
-    fn adapt_interior_ngram(
-        &mut self,
+    fn collect_interior_ngram(
+        &self,
+        rule: &Rule,
         old_idx: usize,
         old_ng: &[char],
         new_ng: &[char],
-        acc: &mut Vec<i32>,
+        deltas: &mut Deltas,
+        hooks: &mut Hooks<'_>,
     ) {
-        let freq = self.get_trigrams()[old_idx];
+        let fraction = interior_fraction(self, rule, old_ng, new_ng);
+        let freq =
+            U::Count::narrow(rule.scale(self.count_at(Table::Trigrams, old_idx).into(), fraction));
         let new_idx = self.corpus_trigram(&[new_ng[0], new_ng[1], new_ng[2]]);
 
-        acc[old_idx] = acc[old_idx].checked_sub_unsigned(freq).expect("Overflow!");
-        acc[new_idx] = acc[new_idx].checked_add_unsigned(freq).expect("Overflow!");
+        deltas.add(Table::Trigrams, old_idx, -freq.delta());
+        deltas.add(Table::Trigrams, new_idx, freq.delta());
+        hooks.record_interior(Table::Trigrams, old_ng, new_ng, freq.delta());
 
         // Skipgrams
         // XXX: Half-assed skipgrams, assumes all corpus chars were valid.
         let old_idx = self.corpus_bigram(&[old_ng[0], old_ng[2]]);
-        self.get_skipgrams()[old_idx] -= freq;
+        deltas.add(Table::Skipgrams, old_idx, -freq.delta());
         let new_sg = &[new_ng[0], new_ng[2]];
         let new_idx = self.corpus_bigram(new_sg);
-        self.get_skipgrams()[new_idx] += freq;
+        deltas.add(Table::Skipgrams, new_idx, freq.delta());
+        #[rustfmt::skip]
+        hooks.record_interior(Table::Skipgrams, &[old_ng[0], old_ng[2]], new_sg, freq.delta());
     }
 }
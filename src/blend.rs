@@ -0,0 +1,164 @@
+// Copyright 2025 antlers <antlers@illucid.net>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Weighted mixes of corpora, eg. 60% prose, 30% chat and 10% code.
+//!
+//! A weight is the share of the blend's chars that a component makes up, so a
+//! small chat corpus isn't drowned out by a large prose one. Each component is
+//! scaled to its share of the total of all of them, then their counts are
+//! summed by ngram into a [`WideCorpus`] whose char list is the union of
+//! theirs (in order of first appearance). A group that shares a char with an
+//! earlier one is merged into it, eg. `[e, é]` into `[e]`.
+//!
+//! Rules can run on the built blend, or on each component before building it
+//! with [`Blend::adapt`]. Adaptation is linear in the counts, so the two only
+//! differ by rounding.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::fs;
+//! use kc::Corpus;
+//! use km_adaptive_corpus::{Adapt, Blend, Rule};
+//!
+//! let read = |path: &str| -> Corpus {
+//!     rmp_serde::from_slice(&fs::read(path).unwrap()).unwrap()
+//! };
+//! let mut blend = Blend::new()
+//!     .with(read("./corpora/shai-iweb.corpus"), 0.7)
+//!     .with(read("./corpora/chat.corpus"), 0.3)
+//!     .build();
+//! blend.adapt(Rule::new(['h', 'e'], ['h', '†']));
+//! ```
+
+use crate::adaptive_corpus::{Adapt, Rule};
+use crate::{CorpusExt, DenseCorpusExt, Table, WideCorpus};
+
+use kc::Corpus;
+
+use tracing::instrument;
+
+/// Corpora and their weights, see the module docs.
+#[derive(Debug, Clone, Default)]
+pub struct Blend {
+    pub components: Vec<(Corpus, f64)>,
+}
+
+impl Blend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Panics if `weight` is negative.
+    pub fn with(mut self, corpus: Corpus, weight: f64) -> Self {
+        assert!(weight >= 0.0, "Negative weight: {weight}");
+        self.components.push((corpus, weight));
+        self
+    }
+
+    /// Adapts every component, see [`Adapt::adapt`].
+    pub fn adapt(&mut self, rule: Rule) {
+        for (corpus, _) in &mut self.components {
+            corpus.adapt(rule);
+        }
+    }
+
+    /// What each component's counts are multiplied by in the blend.
+    pub fn scales(&self) -> Vec<f64> {
+        let totals: Vec<f64> = self
+            .components
+            .iter()
+            .map(|(corpus, _)| {
+                let chars = DenseCorpusExt::table(corpus, Table::Chars);
+                chars.iter().map(|&c| f64::from(c)).sum()
+            })
+            .collect();
+        let total: f64 = totals.iter().sum();
+        let weights: f64 = self.components.iter().map(|(_, w)| w).sum();
+
+        self.components
+            .iter()
+            .zip(totals)
+            .map(|((_, weight), own)| {
+                if own == 0.0 || weights == 0.0 {
+                    0.0
+                } else {
+                    weight / weights * total / own
+                }
+            })
+            .collect()
+    }
+
+    /// Sums the scaled components into one corpus.
+    #[instrument(level = "debug", skip(self))]
+    pub fn build(&self) -> WideCorpus {
+        let scales = self.scales();
+
+        // `with_char_list` puts the placeholder for invalid chars back at index 0
+        let mut char_list: Vec<Vec<char>> = Vec::new();
+        for (corpus, _) in &self.components {
+            for group in &corpus.char_list[1..] {
+                let unknown: Vec<char> = group
+                    .iter()
+                    .copied()
+                    .filter(|c| !char_list.iter().flatten().any(|known| known == c))
+                    .collect();
+                match char_list
+                    .iter_mut()
+                    .find(|known| group.iter().any(|c| known.contains(c)))
+                {
+                    Some(known) => known.extend(unknown),
+                    None => char_list.push(unknown),
+                }
+            }
+        }
+        let mut blend = WideCorpus::from(Corpus::with_char_list(&mut char_list));
+
+        for ((corpus, _), scale) in self.components.iter().zip(scales) {
+            for table in Table::ALL {
+                for (i, &count) in DenseCorpusExt::table(corpus, table).iter().enumerate() {
+                    if count == 0 {
+                        continue;
+                    }
+                    let ngram = corpus.uncorpus_ngram(table.order(), i);
+                    let j = blend.corpus_ngram(&ngram);
+                    blend.get_table(table)[j] += (f64::from(count) * scale).round() as u64;
+                }
+            }
+        }
+        blend
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SparseCorpus;
+
+    use test_log::test;
+
+    fn counted(groups: &[&str], text: &str) -> Corpus {
+        let groups: Vec<Vec<char>> = groups.iter().map(|g| g.chars().collect()).collect();
+        let mut sparse = SparseCorpus::with_char_list(&groups);
+        sparse.add_text(text);
+        Corpus::try_from(&sparse).expect("counts don't fit in a u32")
+    }
+
+    #[test]
+    fn blend_merges_groups() {
+        // Weighed by their sizes, so neither is scaled
+        let plain = counted(&["e", "h", " "], "he he ");
+        let accented = counted(&["eé", "h", "t", " "], "thé ");
+        let blend = Blend::new().with(plain, 6.0).with(accented, 4.0);
+        assert_eq!(blend.scales(), vec![1.0, 1.0]);
+
+        // `é` joins the `e` group rather than being dropped with it
+        let built = blend.build();
+        assert_eq!(built.num_chars(), 5);
+        assert_eq!(built.count_char('é'), 3);
+        assert_eq!(built.count_char('e'), 3);
+        assert_eq!(built.count_bigram(['h', 'e']), 3);
+        assert_eq!(built.count_char('t'), 1);
+    }
+}
@@ -0,0 +1,309 @@
+// Copyright 2025 antlers <antlers@illucid.net>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! An on-disk cache of adaptations, so the same rules aren't re-applied to
+//! the same corpus every session.
+//!
+//! A [`RuleSet`] given a cache with [`RuleSet::with_cache`] looks every
+//! [`RuleSet::adapt`] up in it first. Entries are the sparse changes the rules
+//! made to a corpus, keyed by a hash of the base corpus's counts and a hash of
+//! the rules in the order they're applied, the [`Resolution`], and the version
+//! of this crate. A hit applies the changes instead of adapting, which gives
+//! exactly the corpus adapting would have. Once the cache is over its size,
+//! the least recently used entries are removed.
+//!
+//! Counts are hashed by their chars' positions (see [`corpus_sha256`]) and
+//! stored by their ngrams rather than their indices, so any backend can be
+//! cached, including a [`SparseCorpus`](crate::SparseCorpus) whose indices
+//! depend on the order it first saw its ngrams in. An entry written from one
+//! backend is a hit for the same counts in another.
+//!
+//! The cache is only an optimisation: an entry that can't be read or written
+//! is logged, and the rules are applied as if there was no cache.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::fs;
+//! use kc::Corpus;
+//! use km_adaptive_corpus::cache::AdaptCache;
+//! use km_adaptive_corpus::{Resolution, Rule, RuleSet};
+//!
+//! let b = fs::read("./corpora/shai-iweb.corpus").unwrap();
+//! let mut corpus: Corpus = rmp_serde::from_slice(&b).unwrap();
+//! let cache = AdaptCache::new("/tmp/km_adaptive_corpus/adapted", 1 << 30);
+//! let rules = RuleSet::new(vec![Rule::new(['h', 'e'], ['h', '†'])]).with_cache(cache);
+//! rules.adapt(&mut corpus, Resolution::Ordered).unwrap();
+//! ```
+
+use crate::adaptive_corpus::chain::{ChainError, CycleError, Resolution, RuleSet};
+use crate::adaptive_corpus::dry_run::Deltas;
+use crate::adaptive_corpus::progress::adapt_uncancellable;
+use crate::adaptive_corpus::{Adapt, Hooks};
+use crate::provenance::corpus_sha256;
+use crate::source::sha256_hex;
+use crate::{CorpusExt, Table};
+
+use serde::{Deserialize, Serialize};
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use tracing::{debug, instrument, warn};
+
+/// Bumped whenever an entry's layout changes, older entries are misses.
+pub const FORMAT: u32 = 2;
+
+const EXT: &str = "deltas";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    format: u32,
+    passes: usize,
+    deltas: Vec<(Table, Vec<char>, i64)>,
+}
+
+/// A directory of cached adaptations, at most `max_bytes` in total.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdaptCache {
+    dir: PathBuf,
+    max_bytes: u64,
+}
+
+impl AdaptCache {
+    pub fn new(dir: impl AsRef<Path>, max_bytes: u64) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+            max_bytes,
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The key of adapting `corpus` by `rules`.
+    pub fn key<U: CorpusExt>(
+        corpus: &U,
+        rules: &RuleSet,
+        resolution: Resolution,
+    ) -> Result<String, CycleError> {
+        Ok(format!(
+            "{}-{}",
+            corpus_sha256(corpus),
+            rules_sha256(rules, resolution)?
+        ))
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(key).with_extension(EXT)
+    }
+
+    /// The passes taken and changes made by the adaptation under `key`,
+    /// indexed like `corpus`.
+    ///
+    /// Entries that can't be read, or are in another format, are misses.
+    pub fn get<U: CorpusExt>(&self, key: &str, corpus: &U) -> io::Result<Option<(usize, Deltas)>> {
+        let path = self.path(key);
+        let b = match fs::read(&path) {
+            Ok(b) => b,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let entry: Entry = match rmp_serde::from_slice(&b) {
+            Ok(entry) => entry,
+            Err(e) => {
+                debug!(?path, %e, "unreadable entry");
+                return Ok(None);
+            }
+        };
+        if entry.format != FORMAT {
+            return Ok(None);
+        }
+
+        // Mark it used, for eviction
+        fs::File::options()
+            .write(true)
+            .open(&path)?
+            .set_modified(SystemTime::now())?;
+
+        let mut deltas = Deltas::default();
+        for (table, ngram, d) in entry.deltas {
+            deltas.add(table, corpus.corpus_ngram(&ngram), d);
+        }
+        Ok(Some((entry.passes, deltas)))
+    }
+
+    /// Stores an adaptation of `corpus` under `key`, then evicts down to size.
+    pub fn put<U: CorpusExt>(
+        &self,
+        key: &str,
+        passes: usize,
+        deltas: &Deltas,
+        corpus: &U,
+    ) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let entry = Entry {
+            format: FORMAT,
+            passes,
+            deltas: deltas
+                .iter()
+                .map(|(table, idx, d)| (table, corpus.uncorpus_ngram(table.order(), idx), d))
+                .collect(),
+        };
+        let b = rmp_serde::to_vec(&entry).map_err(io::Error::other)?;
+
+        // Written aside and renamed, so a crash never leaves half an entry
+        let path = self.path(key);
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, b)?;
+        fs::rename(&tmp, &path)?;
+        self.evict()
+    }
+
+    /// Removes the least recently used entries until the cache fits.
+    #[instrument(level = "debug", skip(self))]
+    pub fn evict(&self) -> io::Result<()> {
+        let mut entries = Vec::new();
+        for dirent in fs::read_dir(&self.dir)? {
+            let path = dirent?.path();
+            if path.extension().is_some_and(|ext| ext == EXT) {
+                let meta = fs::metadata(&path)?;
+                entries.push((meta.modified()?, meta.len(), path));
+            }
+        }
+
+        let mut total: u64 = entries.iter().map(|(_, len, _)| len).sum();
+        entries.sort();
+        for (_, len, path) in entries {
+            if total <= self.max_bytes {
+                break;
+            }
+            debug!(?path, "evicting");
+            fs::remove_file(&path)?;
+            total -= len;
+        }
+        Ok(())
+    }
+}
+
+/// SHA-256 of the rules in the order they're applied, and how they're
+/// resolved, in lowercase hex.
+fn rules_sha256(rules: &RuleSet, resolution: Resolution) -> Result<String, CycleError> {
+    let (rules, resolution) = match resolution {
+        Resolution::Ordered => (rules.ordered()?, "ordered".to_string()),
+        Resolution::FixedPoint { max_passes } => {
+            (rules.rules.clone(), format!("fixed-point {max_passes}"))
+        }
+    };
+    let canonical = (FORMAT, env!("CARGO_PKG_VERSION"), resolution, rules);
+    let json = serde_json::to_vec(&canonical).expect("Rules always serialize");
+    Ok(sha256_hex(&json))
+}
+
+impl AdaptCache {
+    /// [`RuleSet::adapt`], looked up here first and stored here on a miss.
+    #[instrument(level = "debug", skip(self, rules, corpus))]
+    pub(crate) fn adapt<U: Adapt>(
+        &self,
+        rules: &RuleSet,
+        corpus: &mut U,
+        resolution: Resolution,
+    ) -> Result<usize, ChainError> {
+        let key = Self::key(corpus, rules, resolution)?;
+        match self.get(&key, corpus) {
+            Ok(Some((passes, deltas))) => {
+                debug!(key, "hit");
+                deltas.apply(corpus);
+                return Ok(passes);
+            }
+            Ok(None) => {}
+            Err(e) => warn!(key, %e, "couldn't read cache entry"),
+        }
+
+        let (passes, undo) = adapt_uncancellable(corpus, rules, resolution, &mut Hooks::default())?;
+
+        // What was actually changed, which is the undo negated
+        let mut deltas = Deltas::default();
+        for (table, idx, d) in undo.iter().flat_map(Deltas::iter) {
+            deltas.add(table, idx, -d);
+        }
+        if let Err(e) = self.put(&key, passes, &deltas, corpus) {
+            warn!(key, %e, "couldn't write cache entry");
+        }
+        Ok(passes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DenseCorpusExt;
+    use crate::testing::{DAGGER_R, HE, MockCorpus, TEXT, cache_dir, sparse_of};
+
+    use test_log::test;
+
+    #[test]
+    fn mock_adapt_cached() {
+        let dir = cache_dir("mock_adapt_cached");
+        let uncached = RuleSet::new(vec![HE, DAGGER_R]);
+        let rules = uncached.clone().with_cache(AdaptCache::new(&dir, 1 << 20));
+
+        let mut expected = MockCorpus::sample();
+        uncached
+            .adapt(&mut expected, Resolution::Ordered)
+            .expect("couldn't adapt corpus");
+
+        // A miss adapts and stores, a hit applies what was stored
+        for _ in 0..2 {
+            let mut mock = MockCorpus::sample();
+            let passes = rules
+                .adapt(&mut mock, Resolution::Ordered)
+                .expect("couldn't adapt corpus");
+            assert_eq!(passes, 1);
+            assert_eq!(mock.tables, expected.tables);
+            assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        }
+
+        // Another resolution is another entry
+        let mut mock = MockCorpus::sample();
+        let fixed_point = Resolution::FixedPoint { max_passes: 4 };
+        rules.adapt(&mut mock, fixed_point).unwrap();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        // The same counts in a sparse corpus are a hit, though it's indexed differently
+        let mut sparse = sparse_of(TEXT);
+        assert_eq!(
+            AdaptCache::key(&sparse, &rules, Resolution::Ordered),
+            AdaptCache::key(&MockCorpus::sample(), &rules, Resolution::Ordered)
+        );
+        rules.adapt(&mut sparse, Resolution::Ordered).unwrap();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        for table in Table::ALL {
+            for (i, &count) in expected.table(table).iter().enumerate() {
+                let ngram = expected.uncorpus_ngram(table.order(), i);
+                let j = sparse.corpus_ngram(&ngram);
+                assert_eq!(
+                    sparse.count_at(table, j),
+                    count,
+                    "{} {ngram:?}",
+                    table.name()
+                );
+            }
+        }
+
+        // Nothing fits, so everything is evicted
+        let cache = AdaptCache::new(&dir, 0);
+        cache.evict().unwrap();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        let mut mock = MockCorpus::sample();
+        uncached
+            .with_cache(cache)
+            .adapt(&mut mock, Resolution::Ordered)
+            .unwrap();
+        assert_eq!(mock.tables, expected.tables);
+    }
+}
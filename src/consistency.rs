@@ -0,0 +1,210 @@
+// Copyright 2025 antlers <antlers@illucid.net>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Checking that a corpus's orders agree with each other.
+//!
+//! Summing the trigrams that start with a bigram gives that bigram's count,
+//! except where the bigram ends a text, so each table is compared with its
+//! [`Marginal`]s of the table above it. A marginal can never be more than the
+//! count, and should only be less by the number of texts the ngram ends (or
+//! starts), which is what `slack` allows for.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::fs;
+//! use kc::Corpus;
+//! use km_adaptive_corpus::consistency::check;
+//!
+//! let b = fs::read("./corpora/shai-iweb.corpus").unwrap();
+//! let corpus: Corpus = rmp_serde::from_slice(&b).unwrap();
+//! for report in check(&corpus, 1000) {
+//!     println!("{} by {:?}: {} off", report.table.name(), report.marginal, report.total);
+//! }
+//! ```
+
+use crate::{CorpusExt, Table};
+
+use std::collections::{BTreeSet, HashMap};
+
+/// How a table's ngrams are summed from the table above it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Marginal {
+    /// Over their last char.
+    Prefix,
+    /// Over their first char.
+    Suffix,
+    /// Trigrams over their middle char, giving skipgrams.
+    Skip,
+}
+
+/// Every table with the table and marginal it's checked against.
+const CHECKS: [(Table, Table, Marginal); 9] = [
+    (Table::Chars, Table::Bigrams, Marginal::Prefix),
+    (Table::Chars, Table::Bigrams, Marginal::Suffix),
+    (Table::Bigrams, Table::Trigrams, Marginal::Prefix),
+    (Table::Bigrams, Table::Trigrams, Marginal::Suffix),
+    (Table::Skipgrams, Table::Trigrams, Marginal::Skip),
+    (Table::Trigrams, Table::Quadgrams, Marginal::Prefix),
+    (Table::Trigrams, Table::Quadgrams, Marginal::Suffix),
+    (Table::Quadgrams, Table::Pentagrams, Marginal::Prefix),
+    (Table::Quadgrams, Table::Pentagrams, Marginal::Suffix),
+];
+
+/// One ngram whose count doesn't match its marginal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Inconsistency {
+    pub ngram: Vec<char>,
+    pub count: u64,
+    pub marginal: u64,
+}
+
+impl Inconsistency {
+    /// How much the count is over its marginal, negative if it's under, which
+    /// edge effects can't explain.
+    pub fn magnitude(&self) -> i64 {
+        self.count as i64 - self.marginal as i64
+    }
+}
+
+/// The result of checking one table against one marginal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    pub table: Table,
+    /// The table the marginal is summed from.
+    pub from: Table,
+    pub marginal: Marginal,
+    /// Sum of every count's distance from its marginal, edge effects included.
+    pub total: u64,
+    /// Largest first.
+    pub inconsistencies: Vec<Inconsistency>,
+}
+
+impl Report {
+    pub fn is_consistent(&self) -> bool {
+        self.inconsistencies.is_empty()
+    }
+}
+
+/// Checks every table, lowest first, against the marginals of the table above.
+///
+/// A count is inconsistent if it's less than its marginal, or more by over
+/// `slack`.
+pub fn check<U: CorpusExt>(corpus: &U, slack: u64) -> Vec<Report> {
+    CHECKS
+        .iter()
+        .map(|&(table, from, marginal)| check_table(corpus, table, from, marginal, slack))
+        .collect()
+}
+
+fn check_table<U: CorpusExt>(
+    corpus: &U,
+    table: Table,
+    from: Table,
+    marginal: Marginal,
+    slack: u64,
+) -> Report {
+    let marginals = marginals(corpus, from, marginal);
+    let indices: BTreeSet<usize> = corpus
+        .nonzero(table)
+        .chain(marginals.keys().copied())
+        .collect();
+
+    let mut total = 0;
+    let mut inconsistencies = Vec::new();
+    for i in indices {
+        let count: u64 = corpus.count_at(table, i).into();
+        let marginal = marginals.get(&i).copied().unwrap_or_default();
+        total += count.abs_diff(marginal);
+        if marginal > count || count - marginal > slack {
+            inconsistencies.push(Inconsistency {
+                ngram: corpus.uncorpus_ngram(table.order(), i),
+                count,
+                marginal,
+            });
+        }
+    }
+    inconsistencies.sort_by_key(|inc| std::cmp::Reverse(inc.magnitude().unsigned_abs()));
+
+    Report {
+        table,
+        from,
+        marginal,
+        total,
+        inconsistencies,
+    }
+}
+
+/// Sums `from` into the table below it, by index.
+fn marginals<U: CorpusExt>(corpus: &U, from: Table, marginal: Marginal) -> HashMap<usize, u64> {
+    let mut sums = HashMap::new();
+    for i in corpus.nonzero(from) {
+        let ngram = corpus.uncorpus_ngram(from.order(), i);
+        let sub = match marginal {
+            Marginal::Prefix => ngram[..ngram.len() - 1].to_vec(),
+            Marginal::Suffix => ngram[1..].to_vec(),
+            Marginal::Skip => vec![ngram[0], ngram[2]],
+        };
+        let count: u64 = corpus.count_at(from, i).into();
+        *sums.entry(corpus.corpus_ngram(&sub)).or_default() += count;
+    }
+    sums
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{HE, MockCorpus};
+    use crate::{Adapt, DenseCorpusExt};
+
+    use kc::Corpus;
+    use std::fs;
+    use test_log::test;
+    use tracing::debug;
+
+    #[test]
+    fn mock_consistency() {
+        let mut mock = MockCorpus::sample();
+        // One text, so each marginal is short by at most one
+        assert!(check(&mock, 1).iter().all(|report| report.is_consistent()));
+
+        let he = mock.corpus_bigram(&['h', 'e']);
+        mock.get_bigrams()[he] += 5;
+        let reports = check(&mock, 1);
+        let chars = reports
+            .iter()
+            .find(|r| r.table == Table::Chars && r.marginal == Marginal::Prefix)
+            .unwrap();
+        let h = Inconsistency {
+            ngram: vec!['h'],
+            count: 9,
+            marginal: 14,
+        };
+        assert_eq!(chars.inconsistencies, [h]);
+        let bigrams = reports
+            .iter()
+            .find(|r| r.table == Table::Bigrams && r.marginal == Marginal::Prefix)
+            .unwrap();
+        assert_eq!(bigrams.inconsistencies[0].ngram, ['h', 'e']);
+        assert_eq!(bigrams.inconsistencies[0].magnitude(), 5);
+    }
+
+    #[test]
+    #[ignore]
+    fn si_he_consistency() {
+        let b = fs::read("./corpora/shai-iweb.corpus").expect("couldn't read corpus file");
+        let mut corpus: Corpus = rmp_serde::from_slice(&b).expect("couldn't deserialize corpus");
+        corpus.adapt(HE);
+        for report in check(&corpus, 1000) {
+            let worst = report.inconsistencies.first();
+            debug!(
+                table = report.table.name(),
+                marginal = ?report.marginal,
+                total = report.total,
+                inconsistent = report.inconsistencies.len(),
+                ?worst
+            );
+        }
+    }
+}
@@ -2,66 +2,307 @@
 //
 // SPDX-License-Identifier: GPL-3.0-only
 
-//! Trait CorpusExt is just a wrapper around struct Corpus's fields.
+//! Trait CorpusExt is just a wrapper around struct Corpus's fields (or any
+//! other backend's).
 
 pub(crate) use kc::Corpus;
 
+use crate::count::Count;
+
+use serde::{Deserialize, Serialize};
+
+/// Names one of Corpus's count tables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Table {
+    Chars,
+    Bigrams,
+    Skipgrams,
+    Trigrams,
+    Quadgrams,
+    Pentagrams,
+}
+
+impl Table {
+    pub const ALL: [Table; 6] = [
+        Table::Chars,
+        Table::Bigrams,
+        Table::Skipgrams,
+        Table::Trigrams,
+        Table::Quadgrams,
+        Table::Pentagrams,
+    ];
+
+    /// Length of the ngrams counted by the table, skipgrams count as bigrams.
+    pub fn order(self) -> usize {
+        match self {
+            Table::Chars => 1,
+            Table::Bigrams | Table::Skipgrams => 2,
+            Table::Trigrams => 3,
+            Table::Quadgrams => 4,
+            Table::Pentagrams => 5,
+        }
+    }
+
+    /// Position in [`Table::ALL`], for backends that keep an array of tables.
+    pub(crate) fn slot(self) -> usize {
+        match self {
+            Table::Chars => 0,
+            Table::Bigrams => 1,
+            Table::Skipgrams => 2,
+            Table::Trigrams => 3,
+            Table::Quadgrams => 4,
+            Table::Pentagrams => 5,
+        }
+    }
+
+    /// The table counting plain ngrams of length `order`.
+    pub fn for_order(order: usize) -> Self {
+        match order {
+            1 => Table::Chars,
+            2 => Table::Bigrams,
+            3 => Table::Trigrams,
+            4 => Table::Quadgrams,
+            5 => Table::Pentagrams,
+            _ => panic!("No such order: {order}"),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Table::Chars => "chars",
+            Table::Bigrams => "bigrams",
+            Table::Skipgrams => "skipgrams",
+            Table::Trigrams => "trigrams",
+            Table::Quadgrams => "quadgrams",
+            Table::Pentagrams => "pentagrams",
+        }
+    }
+}
+
 /// Provides trait implementations on Corpus access to it's struct fields.
+///
+/// Everything that adapts a corpus is written against this trait, so any
+/// backend that can index and un-index ngrams, and count them by index, can
+/// be adapted. Backends with whole tables in memory also implement
+/// [`DenseCorpusExt`].
 pub trait CorpusExt {
-    fn corpus_char(&mut self, char: &[char; 1]) -> usize;
-    fn corpus_bigram(&mut self, bigram: &[char; 2]) -> usize;
-    fn corpus_trigram(&mut self, trigram: &[char; 3]) -> usize;
-    fn corpus_quadgram(&mut self, trigram: &[char; 4]) -> usize;
-    fn corpus_pentagram(&mut self, trigram: &[char; 5]) -> usize;
-    fn get_chars(&mut self) -> &mut Vec<u32>;
-    fn get_bigrams(&mut self) -> &mut Vec<u32>;
-    fn get_trigrams(&mut self) -> &mut Vec<u32>;
-    fn get_skipgrams(&mut self) -> &mut Vec<u32>;
-    fn get_quadgrams(&mut self) -> &mut Vec<u32>;
-    fn get_pentagrams(&mut self) -> &mut Vec<u32>;
+    type Count: Count;
+
+    fn corpus_char(&self, char: &[char; 1]) -> usize;
+    fn corpus_bigram(&self, bigram: &[char; 2]) -> usize;
+    fn corpus_trigram(&self, trigram: &[char; 3]) -> usize;
+    fn corpus_quadgram(&self, trigram: &[char; 4]) -> usize;
+    fn corpus_pentagram(&self, trigram: &[char; 5]) -> usize;
+    /// Index of an ngram of up to five chars, in the table for its length.
+    fn corpus_ngram(&self, ngram: &[char]) -> usize {
+        match *ngram {
+            [a] => self.corpus_char(&[a]),
+            [a, b] => self.corpus_bigram(&[a, b]),
+            [a, b, c] => self.corpus_trigram(&[a, b, c]),
+            [a, b, c, d] => self.corpus_quadgram(&[a, b, c, d]),
+            [a, b, c, d, e] => self.corpus_pentagram(&[a, b, c, d, e]),
+            _ => panic!("No table for ngrams of length {}", ngram.len()),
+        }
+    }
+    fn count_ngram(&self, ngram: &[char]) -> Self::Count {
+        let idx = self.corpus_ngram(ngram);
+        self.count_at(Table::for_order(ngram.len()), idx)
+    }
+    /// The count at `idx` of `table`.
+    fn count_at(&self, table: Table, idx: usize) -> Self::Count;
+    /// Adds `delta` to the count at `idx` of `table`, saturating at zero.
+    fn add_at(&mut self, table: Table, idx: usize, delta: i64);
+    /// Indices of the counts in `table` that aren't zero, in no particular
+    /// order.
+    ///
+    /// Adaptation only visits these, an ngram that never occurs can't
+    /// occur next to a trigger either.
+    fn nonzero(&self, table: Table) -> impl Iterator<Item = usize> + '_;
+    /// Every count in `table` that isn't zero, with its ngram's chars'
+    /// positions in the char list (padded with zeroes), sorted by them.
+    ///
+    /// That's a dense table's index order, so dense backends just read their
+    /// tables, other backends sort. Backends that index the same counts
+    /// differently give the same counts in the same order.
+    fn ordered_counts(&self, table: Table) -> impl Iterator<Item = ([u32; 5], u64)> + '_;
+    /// Groups of chars counted as one, index 0 is for invalid chars.
+    fn char_list(&self) -> &[Vec<char>];
+    /// Length of the char list, valid or not.
+    fn num_chars(&self) -> usize {
+        self.char_list().len()
+    }
+    // The inverses of `corpus_*`
+    fn uncorpus_unigram(&self, idx: usize) -> char;
+    fn uncorpus_bigram(&self, idx: usize) -> Vec<char>;
+    fn uncorpus_trigram(&self, idx: usize) -> Vec<char>;
+    fn uncorpus_quadgram(&self, idx: usize) -> Vec<char>;
+    fn uncorpus_pentagram(&self, idx: usize) -> Vec<char>;
+    /// The ngram at `idx` of the table for ngrams of length `order`.
+    fn uncorpus_ngram(&self, order: usize, idx: usize) -> Vec<char> {
+        match order {
+            1 => vec![self.uncorpus_unigram(idx)],
+            2 => self.uncorpus_bigram(idx),
+            3 => self.uncorpus_trigram(idx),
+            4 => self.uncorpus_quadgram(idx),
+            5 => self.uncorpus_pentagram(idx),
+            _ => panic!("No table for ngrams of length {order}"),
+        }
+    }
     #[cfg(test)]
-    fn count_char(&self, c: char) -> u32;
+    fn count_char(&self, c: char) -> Self::Count;
     #[cfg(test)]
-    fn count_bigram(&self, bg: [char; 2]) -> u32;
+    fn count_bigram(&self, bg: [char; 2]) -> Self::Count;
     #[cfg(test)]
-    fn count_trigram(&self, tg: [char; 3]) -> u32;
+    fn count_trigram(&self, tg: [char; 3]) -> Self::Count;
     #[cfg(test)]
-    fn count_skipgram(&self, sg: [char; 2]) -> u32;
+    fn count_skipgram(&self, sg: [char; 2]) -> Self::Count;
+}
+
+/// A [`CorpusExt`] backed by whole tables, indexed like `kc::Corpus`'s.
+///
+/// Only what reads or rebuilds entire tables needs this, eg. converting
+/// between backends.
+pub trait DenseCorpusExt: CorpusExt {
+    fn get_chars(&mut self) -> &mut Vec<Self::Count>;
+    fn get_bigrams(&mut self) -> &mut Vec<Self::Count>;
+    fn get_trigrams(&mut self) -> &mut Vec<Self::Count>;
+    fn get_skipgrams(&mut self) -> &mut Vec<Self::Count>;
+    fn get_quadgrams(&mut self) -> &mut Vec<Self::Count>;
+    fn get_pentagrams(&mut self) -> &mut Vec<Self::Count>;
+    /// Read-only [`DenseCorpusExt::get_table`].
+    fn table(&self, table: Table) -> &[Self::Count];
+    fn get_table(&mut self, table: Table) -> &mut Vec<Self::Count> {
+        match table {
+            Table::Chars => self.get_chars(),
+            Table::Bigrams => self.get_bigrams(),
+            Table::Skipgrams => self.get_skipgrams(),
+            Table::Trigrams => self.get_trigrams(),
+            Table::Quadgrams => self.get_quadgrams(),
+            Table::Pentagrams => self.get_pentagrams(),
+        }
+    }
+}
+
+/// [`CorpusExt::add_at`] for dense tables.
+pub(crate) fn dense_add_at<U: DenseCorpusExt>(
+    corpus: &mut U,
+    table: Table,
+    idx: usize,
+    delta: i64,
+) {
+    let count = &mut corpus.get_table(table)[idx];
+    *count = count.saturating_add_delta(delta);
+}
+
+/// [`CorpusExt::nonzero`] for dense tables, in index order.
+pub(crate) fn dense_nonzero<C: Count>(counts: &[C]) -> impl Iterator<Item = usize> + '_ {
+    (0..counts.len()).filter(move |&i| counts[i] != C::default())
+}
+
+/// [`CorpusExt::ordered_counts`] for dense tables.
+pub(crate) fn dense_ordered_counts<U: DenseCorpusExt>(
+    corpus: &U,
+    table: Table,
+) -> impl Iterator<Item = ([u32; 5], u64)> + '_ {
+    let counts = corpus.table(table);
+    let n = corpus.num_chars();
+    dense_nonzero(counts).map(move |i| (dense_positions(i, table.order(), n), counts[i].into()))
+}
+
+/// The chars' positions of the ngram of length `order` at `idx` of a dense
+/// table, which counts in base `num_chars`.
+pub(crate) fn dense_positions(mut idx: usize, order: usize, num_chars: usize) -> [u32; 5] {
+    let mut positions = [0; 5];
+    for p in positions[..order].iter_mut().rev() {
+        *p = (idx % num_chars) as u32;
+        idx /= num_chars;
+    }
+    positions
+}
+
+/// A copy of `corpus` with `chars` added to its char list, eg. for the dead
+/// keys typed by a [`Rewrite`](crate::Rewrite).
+///
+/// Counts are carried over by ngram, the new chars start at zero. Chars that
+/// are already in the list are left alone.
+pub fn add_chars(mut corpus: Corpus, chars: &[char]) -> Corpus {
+    let mut missing: Vec<char> = chars
+        .iter()
+        .copied()
+        .filter(|&c| Corpus::corpus_char(&corpus, c) == 0)
+        .collect();
+    missing.sort_unstable();
+    missing.dedup();
+    if missing.is_empty() {
+        return corpus;
+    }
+
+    // `with_char_list` puts the placeholder for invalid chars back at index 0
+    let mut char_list = corpus.char_list[1..].to_vec();
+    char_list.extend(missing.into_iter().map(|c| vec![c]));
+    let mut wider = Corpus::with_char_list(&mut char_list);
+
+    for table in Table::ALL {
+        let counts = std::mem::take(corpus.get_table(table));
+        for (i, count) in counts.into_iter().enumerate() {
+            if count == 0 {
+                continue;
+            }
+            let ngram = corpus.uncorpus_ngram(table.order(), i);
+            let j = wider.corpus_ngram(&ngram);
+            wider.get_table(table)[j] += count;
+        }
+    }
+    wider
 }
 
 impl CorpusExt for Corpus {
-    fn corpus_char(&mut self, char: &[char; 1]) -> usize {
+    type Count = u32;
+
+    fn corpus_char(&self, char: &[char; 1]) -> usize {
         Corpus::corpus_char(self, char[0])
     }
-    fn corpus_bigram(&mut self, bigram: &[char; 2]) -> usize {
+    fn corpus_bigram(&self, bigram: &[char; 2]) -> usize {
         Corpus::corpus_bigram(self, bigram)
     }
-    fn corpus_trigram(&mut self, trigram: &[char; 3]) -> usize {
+    fn corpus_trigram(&self, trigram: &[char; 3]) -> usize {
         Corpus::corpus_trigram(self, trigram)
     }
-    fn corpus_quadgram(&mut self, quadgram: &[char; 4]) -> usize {
+    fn corpus_quadgram(&self, quadgram: &[char; 4]) -> usize {
         Corpus::corpus_quadgram(self, quadgram)
     }
-    fn corpus_pentagram(&mut self, pentagram: &[char; 5]) -> usize {
+    fn corpus_pentagram(&self, pentagram: &[char; 5]) -> usize {
         Corpus::corpus_pentagram(self, pentagram)
     }
-    fn get_chars(&mut self) -> &mut Vec<u32> {
-        &mut self.chars
+    fn count_at(&self, table: Table, idx: usize) -> u32 {
+        self.table(table)[idx]
     }
-    fn get_bigrams(&mut self) -> &mut Vec<u32> {
-        &mut self.bigrams
+    fn add_at(&mut self, table: Table, idx: usize, delta: i64) {
+        dense_add_at(self, table, idx, delta);
     }
-    fn get_trigrams(&mut self) -> &mut Vec<u32> {
-        &mut self.trigrams
+    fn nonzero(&self, table: Table) -> impl Iterator<Item = usize> + '_ {
+        dense_nonzero(self.table(table))
     }
-    fn get_skipgrams(&mut self) -> &mut Vec<u32> {
-        &mut self.skipgrams
+    fn ordered_counts(&self, table: Table) -> impl Iterator<Item = ([u32; 5], u64)> + '_ {
+        dense_ordered_counts(self, table)
     }
-    fn get_quadgrams(&mut self) -> &mut Vec<u32> {
-        &mut self.quadgrams
+    fn char_list(&self) -> &[Vec<char>] {
+        &self.char_list
     }
-    fn get_pentagrams(&mut self) -> &mut Vec<u32> {
-        &mut self.pentagrams
+    fn uncorpus_unigram(&self, idx: usize) -> char {
+        Corpus::uncorpus_unigram(self, idx)
+    }
+    fn uncorpus_bigram(&self, idx: usize) -> Vec<char> {
+        Corpus::uncorpus_bigram(self, idx)
+    }
+    fn uncorpus_trigram(&self, idx: usize) -> Vec<char> {
+        Corpus::uncorpus_trigram(self, idx)
+    }
+    fn uncorpus_quadgram(&self, idx: usize) -> Vec<char> {
+        Corpus::uncorpus_quadgram(self, idx)
+    }
+    fn uncorpus_pentagram(&self, idx: usize) -> Vec<char> {
+        Corpus::uncorpus_pentagram(self, idx)
     }
 
     #[cfg(test)]
@@ -84,3 +325,34 @@ impl CorpusExt for Corpus {
         self.skipgrams[self.corpus_bigram(&sg)]
     }
 }
+
+impl DenseCorpusExt for Corpus {
+    fn table(&self, table: Table) -> &[u32] {
+        match table {
+            Table::Chars => &self.chars,
+            Table::Bigrams => &self.bigrams,
+            Table::Skipgrams => &self.skipgrams,
+            Table::Trigrams => &self.trigrams,
+            Table::Quadgrams => &self.quadgrams,
+            Table::Pentagrams => &self.pentagrams,
+        }
+    }
+    fn get_chars(&mut self) -> &mut Vec<u32> {
+        &mut self.chars
+    }
+    fn get_bigrams(&mut self) -> &mut Vec<u32> {
+        &mut self.bigrams
+    }
+    fn get_trigrams(&mut self) -> &mut Vec<u32> {
+        &mut self.trigrams
+    }
+    fn get_skipgrams(&mut self) -> &mut Vec<u32> {
+        &mut self.skipgrams
+    }
+    fn get_quadgrams(&mut self) -> &mut Vec<u32> {
+        &mut self.quadgrams
+    }
+    fn get_pentagrams(&mut self) -> &mut Vec<u32> {
+        &mut self.pentagrams
+    }
+}
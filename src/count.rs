@@ -0,0 +1,65 @@
+// Copyright 2025 antlers <antlers@illucid.net>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Trait Count abstracts over the integer types a corpus table can hold.
+//!
+//! Deltas between counts are always `i64`, so a single substitution can move
+//! more than `i32::MAX` occurrences without overflowing the accumulators.
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use std::fmt::Debug;
+use std::ops::{AddAssign, Sub, SubAssign};
+
+pub trait Count:
+    Copy
+    + Default
+    + Debug
+    + Ord
+    + Into<u64>
+    + TryFrom<u64>
+    + AddAssign
+    + SubAssign
+    + Sub<Output = Self>
+    + Serialize
+    + DeserializeOwned
+{
+    fn checked_add_delta(self, delta: i64) -> Option<Self>;
+    fn saturating_add_delta(self, delta: i64) -> Self;
+
+    /// The count as a delta, panics if it doesn't fit.
+    fn delta(self) -> i64 {
+        i64::try_from(self.into()).expect("Overflow!")
+    }
+
+    /// Narrows a `u64`, panics if it doesn't fit.
+    fn narrow(count: u64) -> Self {
+        Self::try_from(count).ok().expect("Overflow!")
+    }
+}
+
+impl Count for u32 {
+    fn checked_add_delta(self, delta: i64) -> Option<Self> {
+        i64::from(self)
+            .checked_add(delta)
+            .and_then(|x| u32::try_from(x).ok())
+    }
+
+    fn saturating_add_delta(self, delta: i64) -> Self {
+        i64::from(self)
+            .saturating_add(delta)
+            .clamp(0, i64::from(u32::MAX)) as u32
+    }
+}
+
+impl Count for u64 {
+    fn checked_add_delta(self, delta: i64) -> Option<Self> {
+        self.checked_add_signed(delta)
+    }
+
+    fn saturating_add_delta(self, delta: i64) -> Self {
+        self.saturating_add_signed(delta)
+    }
+}
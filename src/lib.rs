@@ -5,7 +5,62 @@
 pub use kc::Corpus;
 
 pub mod adaptive_corpus;
-pub use adaptive_corpus::AdaptiveCorpus;
+pub use adaptive_corpus::chain::{Resolution, RuleSet};
+pub use adaptive_corpus::context::{Class, Context};
+pub use adaptive_corpus::dry_run::{DeltaSet, Deltas};
+pub use adaptive_corpus::explain::{Explanation, explain};
+pub use adaptive_corpus::magic_key::{MagicKey, adapt_keys};
+pub use adaptive_corpus::marginal::Strategy;
+pub use adaptive_corpus::progress::{AdaptError, CancelToken, Progress, adapt_cancellable};
+pub use adaptive_corpus::{Adapt, AdaptiveCorpus, Cancelled, ExpansionKind, Hooks, Rule};
+
+pub mod blend;
+pub use blend::Blend;
+
+pub mod cache;
+pub use cache::AdaptCache;
+
+pub mod consistency;
 
 pub mod corpus_ext;
-pub use corpus_ext::CorpusExt;
+pub use corpus_ext::{CorpusExt, DenseCorpusExt, Table};
+
+pub mod count;
+pub use count::Count;
+
+pub mod provenance;
+
+pub mod remap;
+pub use remap::Remap;
+
+pub mod restrict;
+
+pub mod rewrite;
+pub use rewrite::{Rewrite, rewrite_corpus};
+
+pub mod sfb_breaker;
+pub use sfb_breaker::SfbBreaker;
+
+pub mod skipgrams;
+pub use skipgrams::Skipgrams;
+
+pub mod source;
+pub use source::CorpusSource;
+
+pub mod sparse_corpus;
+pub use sparse_corpus::SparseCorpus;
+
+pub mod split_corpus;
+pub use split_corpus::SplitCorpus;
+
+#[cfg(test)]
+mod testing;
+
+pub mod units;
+pub use units::{Segmentation, Units};
+
+pub mod wide_corpus;
+pub use wide_corpus::WideCorpus;
+
+pub mod words;
+pub use words::{WordFreqs, WordRule};
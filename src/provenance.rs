@@ -0,0 +1,225 @@
+// Copyright 2025 antlers <antlers@illucid.net>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Adapted corpus files that record how they were made.
+//!
+//! An [`AdaptedCorpus`] is the adapted `kc::Corpus` plus its [`Provenance`]:
+//! the hash of the base corpus, the rules in the order they were applied, the
+//! depth they were applied to, and the version of this crate that did it.
+//! Given the base corpus again, [`AdaptedCorpus::verify`] re-adapts it and
+//! checks that it comes out the same.
+//!
+//! Hashes are SHA-256 over the char list and then each table's counts (see
+//! [`corpus_sha256`]), not over the serialized corpus, so they don't depend on
+//! how the corpus was serialized, or which backend holds it.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::fs;
+//! use kc::Corpus;
+//! use km_adaptive_corpus::Rule;
+//! use km_adaptive_corpus::provenance::AdaptedCorpus;
+//!
+//! let b = fs::read("./corpora/shai-iweb.corpus").unwrap();
+//! let base: Corpus = rmp_serde::from_slice(&b).unwrap();
+//! let rules = vec![Rule::new(['h', 'e'], ['h', '†'])];
+//! let adapted = AdaptedCorpus::adapt(base.clone(), rules, 5);
+//! adapted.save("/tmp/si-he.adapted").unwrap();
+//!
+//! let adapted = AdaptedCorpus::load("/tmp/si-he.adapted").unwrap();
+//! adapted.verify(base).unwrap();
+//! ```
+
+use crate::adaptive_corpus::{Adapt, ORDERS, Rule};
+use crate::source::hex;
+use crate::{CorpusExt, Table};
+
+use kc::Corpus;
+use serde::{Deserialize, Serialize};
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use tracing::instrument;
+
+/// Bumped whenever [`AdaptedCorpus`]'s layout changes.
+pub const FORMAT: u32 = 1;
+
+/// How an [`AdaptedCorpus`] was made.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Provenance {
+    pub format: u32,
+    /// Version of this crate.
+    pub version: String,
+    pub base_sha256: String,
+    /// Of the adapted corpus, to catch files changed since.
+    pub sha256: String,
+    /// In the order they were applied.
+    pub rules: Vec<Rule>,
+    /// Highest order adapted, orders above it are the base corpus's.
+    pub max_depth: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdaptedCorpus {
+    pub provenance: Provenance,
+    pub corpus: Corpus,
+}
+
+/// Why an [`AdaptedCorpus`] doesn't match what it says it was made from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyError {
+    /// The corpus was changed after it was adapted.
+    Corpus { expected: String, found: String },
+    /// The base corpus isn't the one it was adapted from.
+    Base { expected: String, found: String },
+    /// Re-adapting the base corpus gives something else, eg. because it was
+    /// adapted by another version of this crate.
+    Result {
+        expected: String,
+        found: String,
+        version: String,
+    },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::Corpus { expected, found } => {
+                write!(f, "corpus hash is {found}, expected {expected}")
+            }
+            VerifyError::Base { expected, found } => {
+                write!(f, "base corpus hash is {found}, expected {expected}")
+            }
+            VerifyError::Result {
+                expected,
+                found,
+                version,
+            } => write!(
+                f,
+                "re-adapted corpus hash is {found}, expected {expected} (adapted by {version}, \
+                 this is {})",
+                env!("CARGO_PKG_VERSION")
+            ),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+impl AdaptedCorpus {
+    /// Applies `rules` in order to `base`, orders `1..=max_depth` only.
+    #[instrument(level = "debug", skip(base))]
+    pub fn adapt(base: Corpus, rules: Vec<Rule>, max_depth: usize) -> Self {
+        let base_sha256 = corpus_sha256(&base);
+        let mut corpus = base;
+        adapt_to_depth(&mut corpus, &rules, max_depth);
+
+        Self {
+            provenance: Provenance {
+                format: FORMAT,
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                base_sha256,
+                sha256: corpus_sha256(&corpus),
+                rules,
+                max_depth,
+            },
+            corpus,
+        }
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let b = rmp_serde::to_vec(self).map_err(io::Error::other)?;
+        fs::write(path, b)
+    }
+
+    /// Refuses files written in another format.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let b = fs::read(path)?;
+        let adapted: Self = rmp_serde::from_slice(&b).map_err(io::Error::other)?;
+        if adapted.provenance.format != FORMAT {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "adapted corpus format {}, expected {FORMAT}",
+                    adapted.provenance.format
+                ),
+            ));
+        }
+        Ok(adapted)
+    }
+
+    /// Checks the corpus against its own hash, then against `base` re-adapted.
+    #[instrument(level = "debug", skip(self, base))]
+    pub fn verify(&self, mut base: Corpus) -> Result<(), VerifyError> {
+        let p = &self.provenance;
+        let found = corpus_sha256(&self.corpus);
+        if found != p.sha256 {
+            return Err(VerifyError::Corpus {
+                expected: p.sha256.clone(),
+                found,
+            });
+        }
+
+        let found = corpus_sha256(&base);
+        if found != p.base_sha256 {
+            return Err(VerifyError::Base {
+                expected: p.base_sha256.clone(),
+                found,
+            });
+        }
+
+        adapt_to_depth(&mut base, &p.rules, p.max_depth);
+        let found = corpus_sha256(&base);
+        if found != p.sha256 {
+            return Err(VerifyError::Result {
+                expected: p.sha256.clone(),
+                found,
+                version: p.version.clone(),
+            });
+        }
+        Ok(())
+    }
+}
+
+fn adapt_to_depth<U: Adapt>(corpus: &mut U, rules: &[Rule], max_depth: usize) {
+    for &rule in rules {
+        for order in ORDERS.into_iter().filter(|&o| o <= max_depth) {
+            corpus.adapt_order(order, rule);
+        }
+    }
+}
+
+/// SHA-256 over the char list, then every count that isn't zero after its
+/// chars' positions (see [`CorpusExt::ordered_counts`]), as little-endian
+/// integers, in lowercase hex.
+///
+/// Dense tables are hashed as they are, so it's cheap enough to key a cache
+/// by, and backends that index the same counts differently hash the same.
+pub fn corpus_sha256<U: CorpusExt>(corpus: &U) -> String {
+    let mut ctx = ring::digest::Context::new(&ring::digest::SHA256);
+    let mut b = [0; 4];
+    for group in corpus.char_list() {
+        ctx.update(&(group.len() as u64).to_le_bytes());
+        for c in group {
+            ctx.update(c.encode_utf8(&mut b).as_bytes());
+        }
+    }
+    for table in Table::ALL {
+        let order = table.order();
+        for (positions, count) in corpus.ordered_counts(table) {
+            for p in &positions[..order] {
+                ctx.update(&p.to_le_bytes());
+            }
+            ctx.update(&count.to_le_bytes());
+        }
+        // No count is zero, so an entry counting zero ends the table
+        ctx.update(&[0; 28][..4 * order + 8]);
+    }
+
+    hex(ctx.finish())
+}
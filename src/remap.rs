@@ -0,0 +1,89 @@
+// Copyright 2025 antlers <antlers@illucid.net>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Folding chars together, eg. curly quotes into straight ones, in every table.
+//!
+//! Each ngram's count is moved to the ngram with every char remapped, so
+//! skipgrams are remapped like any other bigram. Chains like `a -> b -> c` are
+//! followed to the end, and the chars mapped onto must be in the char list.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::fs;
+//! use kc::Corpus;
+//! use km_adaptive_corpus::Remap;
+//!
+//! let b = fs::read("./corpora/shai-iweb.corpus").unwrap();
+//! let mut corpus: Corpus = rmp_serde::from_slice(&b).unwrap();
+//! corpus.remap(&[('‘', '\''), ('’', '\''), ('q', 'k')]);
+//! ```
+
+use crate::count::Count;
+use crate::{CorpusExt, Table};
+
+use std::collections::HashMap;
+
+use tracing::instrument;
+
+pub trait Remap: CorpusExt {
+    /// Merges the counts of each `(from, to)` pair's `from` into `to`.
+    ///
+    /// Panics if a chain of mappings loops, or ends on a char that isn't in
+    /// the char list.
+    #[instrument(level = "debug", skip(self))]
+    fn remap(&mut self, map: &[(char, char)]) {
+        let map = resolve(map);
+        for &to in map.values() {
+            let idx = self.corpus_char(&[to]);
+            assert!(
+                self.uncorpus_unigram(idx) == to || idx != 0,
+                "{to:?} isn't in the char list"
+            );
+        }
+
+        for table in Table::ALL {
+            let nonzero: Vec<usize> = self.nonzero(table).collect();
+            for i in nonzero {
+                let count = self.count_at(table, i);
+                let ngram = self.uncorpus_ngram(table.order(), i);
+                let mapped: Vec<char> = ngram
+                    .iter()
+                    .map(|c| map.get(c).copied().unwrap_or(*c))
+                    .collect();
+                if mapped == ngram {
+                    continue;
+                }
+
+                // Every mapped char maps to itself, so `j` is never moved again
+                let j = self.corpus_ngram(&mapped);
+                self.count_at(table, j)
+                    .checked_add_delta(count.delta())
+                    .expect("Overflow!");
+                self.add_at(table, i, -count.delta());
+                self.add_at(table, j, count.delta());
+            }
+        }
+    }
+}
+
+impl<U: CorpusExt> Remap for U {}
+
+/// Follows every chain of mappings to the char it ends on.
+fn resolve(map: &[(char, char)]) -> HashMap<char, char> {
+    let direct: HashMap<char, char> = map.iter().copied().filter(|(a, b)| a != b).collect();
+    direct
+        .keys()
+        .map(|&from| {
+            let mut to = direct[&from];
+            for _ in 0..direct.len() {
+                match direct.get(&to) {
+                    Some(&next) => to = next,
+                    None => return (from, to),
+                }
+            }
+            panic!("Remapping {from:?} loops");
+        })
+        .collect()
+}
@@ -0,0 +1,130 @@
+// Copyright 2025 antlers <antlers@illucid.net>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Projecting a corpus onto the chars a layout can type.
+//!
+//! Chars outside the set are either dropped from the text, so that their
+//! neighbours become adjacent, or redirected onto one char of the set (eg. a
+//! space). Either way the result only lists the chars in the set.
+//!
+//! Dropping re-windows every order. An ngram of the restricted text spans some
+//! window of the original whose first and last chars are kept, so each count
+//! of each table is added to the ngram of its kept chars, if its ends are
+//! kept. Windows only go up to pentagrams, so order `k` sees gaps of up to
+//! `5 - k` dropped chars, and pentagrams none; longer gaps are lost.
+//!
+//! Skipgrams skip over dropped chars, like the rest: they're taken from the
+//! restricted trigrams' first and last chars rather than from the original
+//! skipgrams.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::fs;
+//! use kc::Corpus;
+//! use km_adaptive_corpus::restrict::{Others, restrict};
+//!
+//! let b = fs::read("./corpora/shai-iweb.corpus").unwrap();
+//! let corpus: Corpus = rmp_serde::from_slice(&b).unwrap();
+//! let layout = "qwertyuiopasdfghjkl;'zxcvbnm,./ ".chars();
+//! let corpus = restrict(&corpus, layout, Others::Drop);
+//! ```
+//!
+//! Or, for a keymeow layout, [`restrict_to_keymeow`] with its matrix.
+
+use crate::count::Count;
+use crate::{CorpusExt, DenseCorpusExt, Table};
+
+use kc::Corpus;
+
+use std::collections::HashSet;
+
+use tracing::instrument;
+
+/// What happens to chars outside the set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Others {
+    /// Removed from the text, their neighbours become adjacent.
+    Drop,
+    /// Replaced by this char, which must be in the set.
+    Redirect(char),
+}
+
+/// `corpus` with only `chars` in its char list, see the module docs.
+///
+/// A group of the char list (eg. `a` and `A`) is kept if any of its chars are.
+/// `corpus` can be any backend, eg. a [`SparseCorpus`] too big to be dense,
+/// but the result is a dense [`Corpus`], which a layout's chars are few
+/// enough for.
+///
+/// Panics if a count overflows the result's.
+///
+/// [`SparseCorpus`]: crate::SparseCorpus
+#[instrument(level = "debug", skip(corpus, chars))]
+pub fn restrict<U: CorpusExt>(
+    corpus: &U,
+    chars: impl IntoIterator<Item = char>,
+    others: Others,
+) -> Corpus {
+    let chars: HashSet<char> = chars.into_iter().collect();
+    if let Others::Redirect(c) = others {
+        assert!(chars.contains(&c), "Redirecting to {c:?}, which isn't kept");
+    }
+
+    // `with_char_list` puts the placeholder for invalid chars back at index 0
+    let mut char_list: Vec<Vec<char>> = corpus.char_list()[1..]
+        .iter()
+        .filter(|group| group.iter().any(|c| chars.contains(c)))
+        .cloned()
+        .collect();
+    // Ngrams are read back as their group's first char
+    let kept: HashSet<char> = char_list.iter().map(|group| group[0]).collect();
+    let mut restricted = Corpus::with_char_list(&mut char_list);
+
+    let keep = |c: char| -> Option<char> {
+        if kept.contains(&c) {
+            Some(c)
+        } else if let Others::Redirect(to) = others {
+            Some(to)
+        } else {
+            None
+        }
+    };
+
+    for table in Table::ALL.into_iter().filter(|&t| t != Table::Skipgrams) {
+        for i in corpus.nonzero(table) {
+            let count: u64 = corpus.count_at(table, i).into();
+            let window = corpus.uncorpus_ngram(table.order(), i);
+            let (Some(_), Some(_)) = (keep(window[0]), keep(window[window.len() - 1])) else {
+                continue;
+            };
+            let ngram: Vec<char> = window.iter().filter_map(|&c| keep(c)).collect();
+
+            add(
+                &mut restricted,
+                Table::for_order(ngram.len()),
+                &ngram,
+                count,
+            );
+            if let [a, _, c] = *ngram {
+                add(&mut restricted, Table::Skipgrams, &[a, c], count);
+            }
+        }
+    }
+    restricted
+}
+
+/// [`restrict`] to the chars of a keymeow layout's matrix, which lists one
+/// char per key position.
+pub fn restrict_to_keymeow<U: CorpusExt>(corpus: &U, matrix: &[char], others: Others) -> Corpus {
+    restrict(corpus, matrix.iter().copied(), others)
+}
+
+fn add(corpus: &mut Corpus, table: Table, ngram: &[char], count: u64) {
+    let idx = corpus.corpus_ngram(ngram);
+    let counts = corpus.get_table(table);
+    counts[idx] = counts[idx]
+        .checked_add_delta(count.delta())
+        .expect("Overflow!");
+}
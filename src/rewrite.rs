@@ -0,0 +1,383 @@
+// Copyright 2025 antlers <antlers@illucid.net>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Substitutions whose text and keystrokes differ in length.
+//!
+//! A [`Rewrite`] replaces every occurrence of `old` (the text) with `new` (the
+//! keystrokes that type it), eg. an SFB-breaker key that types "ion" is
+//! `Rewrite::new("ion", "†")`. Unlike the bigram [`Rule`]s, this shifts every
+//! window that an occurrence falls into, so each order is rebuilt from the
+//! text windows around the occurrences instead of from fixed expansions.
+//!
+//! For order `k` and a rule of length `a`, each text position `c` owns the
+//! keystroke window(s) that start at it. Its window is only affected if an
+//! occurrence overlaps `c..c + k`, which can be decided from the `a - 1`
+//! chars either side of it (plus one more on each side with a context). So
+//! for every text window of `k + 2(a - 1)` chars with such an occurrence, we
+//! take away the text ngram at `c` and add back the keystroke ngrams emitted
+//! from `c` onwards, reading further ahead when the keystrokes run out before
+//! the window does.
+//!
+//! Text windows longer than five chars aren't in the corpus, their counts
+//! are extended from the pentagrams as an order-4 Markov chain. This is exact
+//! when `new` is at least as long as `old` and the windows fit in a pentagram,
+//! except for occurrences near the ends of the text, whose windows are cut
+//! off.
+//!
+//! `old` is assumed not to overlap itself, and `new` may only use chars that
+//! are already in the corpus's char list; [`rewrite_corpus`] adds them first.
+//!
+//! Single chars expanded into longer keystroke sequences, like dead keys and
+//! compose sequences, never need the Markov estimates: every window of
+//! keystrokes comes from a shorter window of text. See [`Rewrite::dead_key`].
+//!
+//! [`Rule`]: crate::Rule
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::fs;
+//! use kc::Corpus;
+//! use km_adaptive_corpus::Rewrite;
+//!
+//! let b = fs::read("./corpora/shai-iweb.corpus").unwrap();
+//! let mut corpus: Corpus = rmp_serde::from_slice(&b).unwrap();
+//! Rewrite::new("ion", "†").apply(&mut corpus);
+//! ```
+
+use crate::adaptive_corpus::ORDERS;
+use crate::adaptive_corpus::context::Context;
+use crate::corpus_ext::add_chars;
+use crate::{CorpusExt, Table};
+
+use kc::Corpus;
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::iter;
+
+use tracing::instrument;
+
+/// Text `old` is typed as keystrokes `new`.
+///
+/// `before` and `after` restrict it to occurrences with matching neighbours,
+/// like a [`Rule`](crate::Rule)'s. Occurrences at the edge of what the corpus
+/// can see, with no neighbour to check, never match a restricted rewrite.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Rewrite {
+    pub old: Vec<char>,
+    pub new: Vec<char>,
+    pub before: Context,
+    pub after: Context,
+}
+
+/// Applies `rewrites` in order, after adding their keystrokes to the char list.
+///
+/// Each rewrite sees the keystrokes of the ones before it.
+pub fn rewrite_corpus(corpus: Corpus, rewrites: &[Rewrite]) -> Corpus {
+    let keys: Vec<char> = rewrites
+        .iter()
+        .flat_map(|r| r.new.iter().copied())
+        .collect();
+    let mut corpus = add_chars(corpus, &keys);
+    for rewrite in rewrites {
+        rewrite.apply(&mut corpus);
+    }
+    corpus
+}
+
+/// Sparse changes to a corpus's tables, summed before they're applied.
+#[derive(Debug, Default)]
+struct Changes(HashMap<(Table, usize), f64>);
+
+impl Changes {
+    fn add<U: CorpusExt>(&mut self, corpus: &U, ngram: &[char], count: f64) {
+        let idx = corpus.corpus_ngram(ngram);
+        *self
+            .0
+            .entry((Table::for_order(ngram.len()), idx))
+            .or_default() += count;
+
+        if let [a, _, c] = *ngram {
+            let idx = corpus.corpus_bigram(&[a, c]);
+            *self.0.entry((Table::Skipgrams, idx)).or_default() += count;
+        }
+    }
+
+    /// Counts are scaled and rounded, and clamped to zero where the Markov
+    /// estimates took away more than was there.
+    fn apply<U: CorpusExt>(self, corpus: &mut U, scale: f64) {
+        for ((table, idx), delta) in self.0 {
+            corpus.add_at(table, idx, (delta * scale).round() as i64);
+        }
+    }
+}
+
+impl Rewrite {
+    pub fn new(old: &str, new: &str) -> Self {
+        let rewrite = Self {
+            old: old.chars().collect(),
+            new: new.chars().collect(),
+            before: Context::Any,
+            after: Context::Any,
+        };
+        assert!(!rewrite.old.is_empty(), "Rewrite of empty text");
+        assert!(!rewrite.new.is_empty(), "Rewrite to empty keystrokes");
+        rewrite
+    }
+
+    /// `c` is typed as the keystrokes `keys`, eg. `é` as `´` then `e`.
+    pub fn dead_key(c: char, keys: &str) -> Self {
+        Self::new(c.encode_utf8(&mut [0; 4]), keys)
+    }
+
+    pub fn with_before(self, before: Context) -> Self {
+        Self { before, ..self }
+    }
+
+    pub fn with_after(self, after: Context) -> Self {
+        Self { after, ..self }
+    }
+
+    /// Applies the rewrite to every order of `corpus`.
+    ///
+    /// Every order is computed from the original tables before any are
+    /// changed, so they can be applied in any order.
+    pub fn apply<U: CorpusExt>(&self, corpus: &mut U) {
+        self.apply_scaled(corpus, 1.0);
+    }
+
+    /// Applies the rewrite with every change multiplied by `scale`, eg. to
+    /// correct the estimated number of occurrences.
+    #[instrument(level = "debug", skip(corpus))]
+    pub(crate) fn apply_scaled<U: CorpusExt>(&self, corpus: &mut U, scale: f64) {
+        let mut changes = Changes::default();
+        for order in ORDERS {
+            self.collect(corpus, order, &mut changes);
+        }
+        changes.apply(corpus, scale);
+    }
+
+    /// Estimated number of occurrences in `corpus`, with their contexts.
+    pub(crate) fn occurrences<U: CorpusExt>(&self, corpus: &U) -> f64 {
+        let alphabet = alphabet(corpus);
+        let mut windows = vec![self.old.clone()];
+        if self.before != Context::Any {
+            windows = extend(&windows, &alphabet, |c, w| iter::once(c).chain(w).collect());
+        }
+        if self.after != Context::Any {
+            windows = extend(&windows, &alphabet, |c, w| {
+                w.into_iter().chain([c]).collect()
+            });
+        }
+        windows
+            .iter()
+            .filter(|w| self.matches_at(w, self.reach().0))
+            .map(|w| text_count(corpus, w))
+            .sum()
+    }
+
+    /// Chars needed either side of an occurrence to check its contexts.
+    fn reach(&self) -> (usize, usize) {
+        (
+            usize::from(self.before != Context::Any),
+            usize::from(self.after != Context::Any),
+        )
+    }
+
+    /// Whether an occurrence starts at `text[i]`, contexts included.
+    fn matches_at(&self, text: &[char], i: usize) -> bool {
+        let allows = |ctx: Context, c: Option<&char>| {
+            ctx == Context::Any || c.is_some_and(|&c| ctx.allows(c))
+        };
+        let a = self.old.len();
+        text.get(i..i + a) == Some(&self.old[..])
+            && allows(self.before, i.checked_sub(1).and_then(|j| text.get(j)))
+            && allows(self.after, text.get(i + a))
+    }
+
+    /// Adds what the rewrite changes in order `k` to `changes`.
+    fn collect<U: CorpusExt>(&self, corpus: &U, k: usize, changes: &mut Changes) {
+        let walk = Walk {
+            rewrite: self,
+            alphabet: alphabet(corpus),
+            k,
+        };
+        let a = self.old.len();
+        let (lb, rb) = self.reach();
+        let c = a - 1 + lb;
+        let len = c + k + a - 1 + rb;
+
+        // Every window is visited once, for the first occurrence that overlaps `c..c + k`
+        for q in lb..c + k {
+            let mut visit = |corpus: &U, window: &[char], count: f64| {
+                if self.first_overlap(window, c, k) != Some(q) {
+                    return;
+                }
+
+                changes.add(corpus, &window[c..c + k], -count);
+
+                let matches = self.matches(window);
+                if matches.iter().any(|&m| m < c && c < m + a) {
+                    // Inside an occurrence, no keystrokes start here
+                } else if matches.contains(&c) {
+                    for j in 0..self.new.len() {
+                        let keys = self.new[j..].to_vec();
+                        walk.emit(corpus, window.to_vec(), count, c + a, keys, changes);
+                    }
+                } else {
+                    let keys = vec![window[c]];
+                    walk.emit(corpus, window.to_vec(), count, c + 1, keys, changes);
+                }
+            };
+            walk.windows(corpus, self.old.clone(), q, len - q - a, &mut visit);
+        }
+    }
+
+    /// Start of each occurrence of `old` in `text`, leftmost first.
+    fn matches(&self, text: &[char]) -> Vec<usize> {
+        let a = self.old.len();
+        let mut matches = Vec::new();
+        let mut i = 0;
+        while i + a <= text.len() {
+            if self.matches_at(text, i) {
+                matches.push(i);
+                i += a;
+            } else {
+                i += 1;
+            }
+        }
+        matches
+    }
+
+    /// Start of the first occurrence overlapping `text[c..c + k]`.
+    fn first_overlap(&self, text: &[char], c: usize, k: usize) -> Option<usize> {
+        let a = self.old.len();
+        self.matches(text)
+            .into_iter()
+            .find(|&m| m + a > c && m < c + k)
+    }
+}
+
+/// Walks the text around occurrences of a [`Rewrite`], for one order `k`.
+struct Walk<'a> {
+    rewrite: &'a Rewrite,
+    alphabet: Vec<char>,
+    k: usize,
+}
+
+impl Walk<'_> {
+    /// Visits every window with `left` and `right` more chars around `text`.
+    fn windows<U: CorpusExt>(
+        &self,
+        corpus: &U,
+        text: Vec<char>,
+        left: usize,
+        right: usize,
+        visit: &mut impl FnMut(&U, &[char], f64),
+    ) {
+        let count = text_count(corpus, &text);
+        if count == 0.0 {
+            return;
+        }
+
+        if right > 0 {
+            for &c in &self.alphabet {
+                let mut next = text.clone();
+                next.push(c);
+                self.windows(corpus, next, left, right - 1, visit);
+            }
+        } else if left > 0 {
+            for &c in &self.alphabet {
+                let mut next = vec![c];
+                next.extend(&text);
+                self.windows(corpus, next, left - 1, 0, visit);
+            }
+        } else {
+            visit(corpus, &text, count);
+        }
+    }
+
+    /// Emits keystrokes for `text[pos..]` onto `keys` until there are `k` of
+    /// them, reading further into the text where it runs out.
+    fn emit<U: CorpusExt>(
+        &self,
+        corpus: &U,
+        text: Vec<char>,
+        count: f64,
+        mut pos: usize,
+        mut keys: Vec<char>,
+        changes: &mut Changes,
+    ) {
+        let old = &self.rewrite.old;
+        let (_, rb) = self.rewrite.reach();
+        while keys.len() < self.k {
+            let rest = &text[pos.min(text.len())..];
+            let head = &rest[..rest.len().min(old.len())];
+            if rest.len() < old.len() + rb && old.starts_with(head) {
+                // Can't tell whether an occurrence starts here yet
+                for &c in &self.alphabet {
+                    let mut next = text.clone();
+                    next.push(c);
+                    let n = text_count(corpus, &next);
+                    if n != 0.0 {
+                        self.emit(corpus, next, n, pos, keys.clone(), changes);
+                    }
+                }
+                return;
+            } else if self.rewrite.matches_at(&text, pos) {
+                keys.extend(&self.rewrite.new);
+                pos += old.len();
+            } else {
+                keys.push(rest[0]);
+                pos += 1;
+            }
+        }
+        changes.add(corpus, &keys[..self.k], count);
+    }
+}
+
+fn alphabet<U: CorpusExt>(corpus: &U) -> Vec<char> {
+    (0..corpus.num_chars())
+        .map(|i| corpus.uncorpus_unigram(i))
+        .collect()
+}
+
+/// Every window in `windows`, extended by every char of `alphabet`.
+fn extend(
+    windows: &[Vec<char>],
+    alphabet: &[char],
+    f: impl Fn(char, Vec<char>) -> Vec<char>,
+) -> Vec<Vec<char>> {
+    windows
+        .iter()
+        .flat_map(|w| alphabet.iter().map(|&c| f(c, w.clone())))
+        .collect()
+}
+
+/// Count of `text` in the corpus, extended past pentagrams as a Markov chain.
+pub(crate) fn text_count<U: CorpusExt>(corpus: &U, text: &[char]) -> f64 {
+    fn count<U: CorpusExt>(corpus: &U, ngram: &[char]) -> f64 {
+        let count: u64 = corpus.count_ngram(ngram).into();
+        count as f64
+    }
+
+    if text.len() <= 5 {
+        return count(corpus, text);
+    }
+
+    let mut n = count(corpus, &text[..5]);
+    for j in 5..text.len() {
+        if n == 0.0 {
+            break;
+        }
+        let prefix = count(corpus, &text[j - 4..j]);
+        n = if prefix == 0.0 {
+            0.0
+        } else {
+            n * count(corpus, &text[j - 4..=j]) / prefix
+        };
+    }
+    n
+}
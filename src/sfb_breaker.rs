@@ -0,0 +1,129 @@
+// Copyright 2025 antlers <antlers@illucid.net>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Positional magic keys that break same-finger bigrams.
+//!
+//! "Whenever the next key would be on the same finger, press magic instead."
+//! A magic key can only type one thing after each char, so for every trigger
+//! the generated rule picks its most frequent same-finger follower in the
+//! corpus. The rules come out most frequent first, and can be cut down to the
+//! top `n`.
+//!
+//! Finger assignments can be anything comparable, eg. keymeow's `Finger` via
+//! [`SfbBreaker::from_keymeow`], or plain column numbers.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::fs;
+//! use kc::Corpus;
+//! use km_adaptive_corpus::{Resolution, SfbBreaker, adapt_keys};
+//!
+//! let b = fs::read("./corpora/shai-iweb.corpus").unwrap();
+//! let mut corpus: Corpus = rmp_serde::from_slice(&b).unwrap();
+//! let columns = ["qaz", "wsx", "edc", "rfvtgb", "yhnujm", "ik,", "ol.", "p;/"];
+//! let fingers = columns
+//!     .iter()
+//!     .enumerate()
+//!     .flat_map(|(f, keys)| keys.chars().map(move |c| (c, f)));
+//! let key = SfbBreaker::new(fingers).with_top(32).generate(&mut corpus, '†');
+//! adapt_keys(&mut corpus, &[key], Resolution::Ordered).unwrap();
+//! ```
+
+use crate::CorpusExt;
+use crate::adaptive_corpus::Rule;
+use crate::adaptive_corpus::magic_key::MagicKey;
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use tracing::instrument;
+
+/// Builds a [`MagicKey`] from which chars share a finger.
+#[derive(Debug, Clone)]
+pub struct SfbBreaker<F> {
+    fingers: HashMap<char, F>,
+    repeats: bool,
+    top: Option<usize>,
+}
+
+impl<F: Eq + Hash> SfbBreaker<F> {
+    pub fn new(fingers: impl IntoIterator<Item = (char, F)>) -> Self {
+        Self {
+            fingers: fingers.into_iter().collect(),
+            repeats: false,
+            top: None,
+        }
+    }
+
+    /// Also break same-key repeats, eg. `ll`, like a repeat key would.
+    pub fn with_repeats(self, repeats: bool) -> Self {
+        Self { repeats, ..self }
+    }
+
+    /// Only keep the `n` most frequent rules.
+    pub fn with_top(self, n: usize) -> Self {
+        Self {
+            top: Some(n),
+            ..self
+        }
+    }
+
+    /// Every same-finger bigram, in no particular order.
+    pub fn pairs(&self) -> Vec<[char; 2]> {
+        let mut pairs = Vec::new();
+        for (&a, fa) in &self.fingers {
+            for (&b, fb) in &self.fingers {
+                if fa == fb && (a != b || self.repeats) {
+                    pairs.push([a, b]);
+                }
+            }
+        }
+        pairs
+    }
+
+    /// The rules, most frequent first, as a key typing `placeholder`.
+    #[instrument(level = "debug", skip(self, corpus))]
+    pub fn generate<U: CorpusExt>(&self, corpus: &mut U, placeholder: char) -> MagicKey {
+        let mut best: HashMap<char, ([char; 2], u64)> = HashMap::new();
+        for pair in self.pairs() {
+            let count: u64 = corpus.count_ngram(&pair).into();
+            if count == 0 {
+                continue;
+            }
+            let entry = best.entry(pair[0]).or_insert((pair, 0));
+            // Ties go to the lower char, so the result doesn't depend on hash order
+            if count > entry.1 || (count == entry.1 && pair[1] < entry.0[1]) {
+                *entry = (pair, count);
+            }
+        }
+
+        let mut rules: Vec<([char; 2], u64)> = best.into_values().collect();
+        rules.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        if let Some(n) = self.top {
+            rules.truncate(n);
+        }
+
+        let mut key = MagicKey::new("sfb", placeholder);
+        for (pair, _) in rules {
+            key = key.with(Rule::new(pair, [pair[0], placeholder]));
+        }
+        key
+    }
+}
+
+impl SfbBreaker<km::Finger> {
+    /// Fingers from a keymeow layout's matrix and its keyboard's fingering,
+    /// which list one char and one finger per key position.
+    ///
+    /// Panics if they don't have the same number of positions.
+    pub fn from_keymeow(matrix: &[char], fingers: &[km::Finger]) -> Self {
+        assert_eq!(
+            matrix.len(),
+            fingers.len(),
+            "Matrix and fingering don't match"
+        );
+        Self::new(matrix.iter().copied().zip(fingers.iter().copied()))
+    }
+}
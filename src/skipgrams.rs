@@ -0,0 +1,75 @@
+// Copyright 2025 antlers <antlers@illucid.net>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Bigrams with more than one char between them, for skipgram decay metrics.
+//!
+//! Corpora only store 1-skipgrams, so 2-skipgrams are summed from the
+//! quadgrams they start and end, and 3-skipgrams from the pentagrams. Derive
+//! them after adapting and they reflect the rules, as far as the quadgrams and
+//! pentagrams do.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::fs;
+//! use kc::Corpus;
+//! use km_adaptive_corpus::{Adapt, Rule, Skipgrams};
+//!
+//! let b = fs::read("./corpora/shai-iweb.corpus").unwrap();
+//! let mut corpus: Corpus = rmp_serde::from_slice(&b).unwrap();
+//! corpus.adapt(Rule::new(['h', 'e'], ['h', '†']));
+//! let skip2 = corpus.skipgrams(2);
+//! let t_dagger = corpus.corpus_bigram(&['t', '†']);
+//! println!("t__†: {}", skip2.get(&t_dagger).unwrap_or(&0));
+//! ```
+
+use crate::{CorpusExt, Table};
+
+use std::collections::HashMap;
+
+/// The largest skip that can be derived from a pentagram corpus.
+pub const MAX_SKIP: usize = 3;
+
+pub trait Skipgrams: CorpusExt {
+    /// Counts of pairs of chars with `skip` chars between them, by bigram
+    /// index. Only pairs that occur are included.
+    ///
+    /// Skip 0 is the bigrams and skip 1 the stored skipgrams, panics past
+    /// [`MAX_SKIP`].
+    fn skipgrams(&self, skip: usize) -> HashMap<usize, u64> {
+        let table = match skip {
+            0 => Table::Bigrams,
+            1 => Table::Skipgrams,
+            2 => Table::Quadgrams,
+            3 => Table::Pentagrams,
+            _ => panic!("Can't derive {skip}-skipgrams, the largest skip is {MAX_SKIP}"),
+        };
+        if skip < 2 {
+            return self
+                .nonzero(table)
+                .map(|i| (i, self.count_at(table, i).into()))
+                .collect();
+        }
+
+        let mut counts = HashMap::new();
+        for i in self.nonzero(table) {
+            let ngram = self.uncorpus_ngram(table.order(), i);
+            let j = self.corpus_bigram(&[ngram[0], ngram[skip + 1]]);
+            let count: u64 = self.count_at(table, i).into();
+            *counts.entry(j).or_default() += count;
+        }
+        counts
+    }
+
+    /// Count of one pair of chars with `skip` chars between them.
+    ///
+    /// Sums the whole table for skips past 1, use [`Skipgrams::skipgrams`]
+    /// to look up more than a few.
+    fn count_skip(&self, skip: usize, sg: [char; 2]) -> u64 {
+        let idx = self.corpus_bigram(&sg);
+        self.skipgrams(skip).get(&idx).copied().unwrap_or_default()
+    }
+}
+
+impl<U: CorpusExt> Skipgrams for U {}
@@ -0,0 +1,345 @@
+// Copyright 2025 antlers <antlers@illucid.net>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Fetching corpora (or the text they're made from) into a local cache.
+//!
+//! A [`Manifest`] names each file with the URL it comes from and its SHA-256.
+//! [`CorpusSource::fetch`] downloads a file once, checks it against the
+//! manifest, and keeps it in the cache directory; after that it's served from
+//! the cache, which is re-checked on every use. With
+//! [`CorpusSource::offline`], nothing is ever downloaded.
+//!
+//! Manifests are JSON:
+//!
+//! ```json
+//! {
+//!     "shai-iweb": {
+//!         "url": "https://example.com/shai-iweb.corpus",
+//!         "sha256": "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08",
+//!         "kind": "corpus"
+//!     }
+//! }
+//! ```
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use km_adaptive_corpus::source::{CorpusSource, Manifest};
+//!
+//! let manifest = Manifest::read("./corpora/manifest.json").unwrap();
+//! let source = CorpusSource::new("/tmp/km_adaptive_corpus/cache", manifest);
+//! let corpus = source.load_corpus("shai-iweb").unwrap();
+//! ```
+
+use kc::Corpus;
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use tracing::{debug, instrument};
+
+/// What's at the end of a manifest entry's URL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    /// A msgpack-serialized `kc::Corpus`.
+    Corpus,
+    /// UTF-8 text.
+    Text,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    pub url: String,
+    /// Lowercase hex.
+    pub sha256: String,
+    pub kind: Kind,
+}
+
+/// Every file a [`CorpusSource`] can fetch, by name.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Manifest {
+    pub entries: HashMap<String, Entry>,
+}
+
+impl Manifest {
+    pub fn from_json(json: &str) -> io::Result<Self> {
+        serde_json::from_str(json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn read(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::from_json(&fs::read_to_string(path)?)
+    }
+
+    pub fn insert(&mut self, name: &str, entry: Entry) {
+        self.entries.insert(name.to_string(), entry);
+    }
+}
+
+/// A manifest plus the directory its files are cached in.
+#[derive(Debug, Clone)]
+pub struct CorpusSource {
+    cache: PathBuf,
+    manifest: Manifest,
+    offline: bool,
+}
+
+impl CorpusSource {
+    pub fn new(cache: impl AsRef<Path>, manifest: Manifest) -> Self {
+        Self {
+            cache: cache.as_ref().to_path_buf(),
+            manifest,
+            offline: false,
+        }
+    }
+
+    /// Only ever read from the cache.
+    pub fn offline(self) -> Self {
+        Self {
+            offline: true,
+            ..self
+        }
+    }
+
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    /// Where `name` is cached, whether it's been fetched or not.
+    pub fn path(&self, name: &str) -> io::Result<PathBuf> {
+        let entry = self.entry(name)?;
+        let ext = match entry.kind {
+            Kind::Corpus => "corpus",
+            Kind::Text => "txt",
+        };
+        Ok(self.cache.join(format!("{name}.{ext}")))
+    }
+
+    /// Downloads `name` unless it's already cached, returns its cached path.
+    ///
+    /// A cached file that no longer matches its checksum is downloaded again
+    /// (or is an error, offline). So is a download that doesn't match.
+    #[instrument(level = "debug", skip(self))]
+    pub fn fetch(&self, name: &str) -> io::Result<PathBuf> {
+        let entry = self.entry(name)?;
+        let path = self.path(name)?;
+
+        let cached = match fs::read(&path) {
+            Ok(b) if sha256_hex(&b) == entry.sha256 => return Ok(path),
+            Ok(b) => {
+                debug!(?path, "cached file doesn't match its checksum");
+                Some(sha256_hex(&b))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+
+        if self.offline {
+            return Err(match cached {
+                Some(sha256) => io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "cached {name} has checksum {sha256}, expected {}",
+                        entry.sha256
+                    ),
+                ),
+                None => io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{name} isn't cached, and the source is offline"),
+                ),
+            });
+        }
+
+        let response = minreq::get(&entry.url).send().map_err(io::Error::other)?;
+        if !(200..300).contains(&response.status_code) {
+            return Err(io::Error::other(format!(
+                "fetching {}: {} {}",
+                entry.url, response.status_code, response.reason_phrase
+            )));
+        }
+        let b = response.into_bytes();
+        let sha256 = sha256_hex(&b);
+        if sha256 != entry.sha256 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{name} has checksum {sha256}, expected {}", entry.sha256),
+            ));
+        }
+
+        // Written aside and renamed, so a partial download is never cached
+        fs::create_dir_all(&self.cache)?;
+        let partial = path.with_extension("partial");
+        fs::write(&partial, b)?;
+        fs::rename(partial, &path)?;
+        Ok(path)
+    }
+
+    /// Fetches and deserializes a [`Kind::Corpus`].
+    pub fn load_corpus(&self, name: &str) -> io::Result<Corpus> {
+        self.expect_kind(name, Kind::Corpus)?;
+        let b = fs::read(self.fetch(name)?)?;
+        rmp_serde::from_slice(&b).map_err(io::Error::other)
+    }
+
+    /// Fetches a [`Kind::Text`].
+    pub fn load_text(&self, name: &str) -> io::Result<String> {
+        self.expect_kind(name, Kind::Text)?;
+        fs::read_to_string(self.fetch(name)?)
+    }
+
+    fn entry(&self, name: &str) -> io::Result<&Entry> {
+        self.manifest.entries.get(name).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{name} isn't in the manifest"),
+            )
+        })
+    }
+
+    fn expect_kind(&self, name: &str, kind: Kind) -> io::Result<()> {
+        let entry = self.entry(name)?;
+        if entry.kind == kind {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{name} is {:?}, not {kind:?}", entry.kind),
+            ))
+        }
+    }
+}
+
+/// SHA-256 of `b`, in lowercase hex.
+pub fn sha256_hex(b: &[u8]) -> String {
+    hex(ring::digest::digest(&ring::digest::SHA256, b))
+}
+
+/// A digest in lowercase hex.
+pub(crate) fn hex(digest: ring::digest::Digest) -> String {
+    digest
+        .as_ref()
+        .iter()
+        .fold(String::with_capacity(64), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::cache_dir;
+
+    use test_log::test;
+
+    /// Serves `body` over HTTP on localhost, returns its URL and a request counter.
+    fn serve(body: Vec<u8>) -> (String, std::sync::Arc<std::sync::atomic::AtomicUsize>) {
+        use std::io::{BufRead, BufReader, Write};
+        use std::net::TcpListener;
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let listener = TcpListener::bind("127.0.0.1:0").expect("couldn't bind test server");
+        let url = format!("http://{}/file", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { continue };
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                while reader.read_line(&mut line).is_ok_and(|n| n > 2) {
+                    line.clear();
+                }
+                counter.fetch_add(1, Ordering::SeqCst);
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                let _ = stream.write_all(head.as_bytes());
+                let _ = stream.write_all(&body);
+            }
+        });
+        (url, requests)
+    }
+
+    #[test]
+    fn source_fetches_once() {
+        let text = b"the quick brown fox".to_vec();
+        let (url, requests) = serve(text.clone());
+        let mut manifest = Manifest::default();
+        manifest.insert(
+            "fox",
+            Entry {
+                url,
+                sha256: sha256_hex(&text),
+                kind: Kind::Text,
+            },
+        );
+
+        let dir = cache_dir("source_fetches_once");
+        let source = CorpusSource::new(&dir, manifest.clone());
+        assert_eq!(source.load_text("fox").unwrap(), "the quick brown fox");
+        assert_eq!(source.load_text("fox").unwrap(), "the quick brown fox");
+        assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        // Served from the cache offline, unless the cache is tampered with
+        let offline = CorpusSource::new(&dir, manifest).offline();
+        assert_eq!(offline.load_text("fox").unwrap(), "the quick brown fox");
+        fs::write(offline.path("fox").unwrap(), "the slow brown fox").unwrap();
+        let err = offline
+            .load_text("fox")
+            .expect_err("loaded a tampered file");
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(offline.load_corpus("fox").is_err());
+
+        // Missing altogether is another error
+        fs::remove_file(offline.path("fox").unwrap()).unwrap();
+        let err = offline.load_text("fox").expect_err("loaded a missing file");
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn source_rejects_bad_checksums() {
+        let (url, _) = serve(b"not what was promised".to_vec());
+        let manifest = Manifest::from_json(&format!(
+            r#"{{"fox": {{"url": "{url}", "sha256": "{}", "kind": "text"}}}}"#,
+            sha256_hex(b"the quick brown fox")
+        ))
+        .expect("couldn't parse manifest");
+
+        let source = CorpusSource::new(cache_dir("source_rejects_bad_checksums"), manifest);
+        let err = source
+            .fetch("fox")
+            .expect_err("fetched a file with the wrong checksum");
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(!source.path("fox").unwrap().exists());
+    }
+
+    #[test]
+    fn source_loads_corpora() {
+        let mut char_list = vec![vec!['a'], vec!['b']];
+        let corpus = Corpus::with_char_list(&mut char_list);
+        let b = rmp_serde::to_vec(&corpus).expect("couldn't serialize corpus");
+        let (url, _) = serve(b.clone());
+        let mut manifest = Manifest::default();
+        manifest.insert(
+            "ab",
+            Entry {
+                url,
+                sha256: sha256_hex(&b),
+                kind: Kind::Corpus,
+            },
+        );
+
+        let source = CorpusSource::new(cache_dir("source_loads_corpora"), manifest);
+        let loaded = source.load_corpus("ab").expect("couldn't load corpus");
+        assert_eq!(loaded.char_list, corpus.char_list);
+    }
+}
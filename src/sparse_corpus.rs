@@ -0,0 +1,465 @@
+// Copyright 2025 antlers <antlers@illucid.net>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! A corpus that only stores the ngrams that occur, for large alphabets.
+//!
+//! Dense tables have `chars^5` pentagrams, which stops fitting in memory
+//! somewhere past a hundred chars. `SparseCorpus` keeps a hash map per table
+//! instead, and adapts through [`CorpusExt::count_at`],
+//! [`CorpusExt::add_at`] and [`CorpusExt::nonzero`].
+//!
+//! It has no dense tables, so it isn't a [`DenseCorpusExt`], and anything
+//! that takes a `Corpus`, eg.
+//! [`AdaptedCorpus`](crate::provenance::AdaptedCorpus), needs converting it
+//! first. That works for alphabets small enough to be dense.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::fs;
+//! use kc::Corpus;
+//! use km_adaptive_corpus::{Adapt, Rule, SparseCorpus};
+//!
+//! let b = fs::read("./corpora/shai-iweb.corpus").unwrap();
+//! let corpus: Corpus = rmp_serde::from_slice(&b).unwrap();
+//! let mut sparse = SparseCorpus::from(&corpus);
+//! sparse.adapt(Rule::new(['h', 'e'], ['h', '†']));
+//! let corpus = Corpus::try_from(&sparse).expect("counts don't fit in a u32");
+//! ```
+
+use crate::adaptive_corpus::ORDERS;
+use crate::count::Count;
+use crate::wide_corpus::NarrowingError;
+use crate::{CorpusExt, DenseCorpusExt, Table};
+
+use kc::Corpus;
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+
+/// Char ids of an ngram, padded with [`NONE`] past its end.
+type Key = [u32; 5];
+
+const NONE: u32 = u32::MAX;
+
+/// Counts of present ngrams, by table and ngram.
+///
+/// Ngrams are keyed by their chars' ids, so the alphabet can be as large as
+/// Unicode. Chars are indexed by their position in the char list, like
+/// `kc::Corpus`, but longer ngrams are numbered as their index is first
+/// looked up, and keep it for as long as the corpus lives. Counts read by
+/// ngram don't need one.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SparseCorpus {
+    /// Index 0 is for invalid chars, like `kc::Corpus`.
+    char_list: Vec<Vec<char>>,
+    char_map: HashMap<char, u32>,
+    tables: [HashMap<Key, u64>; 6],
+    #[serde(skip)]
+    ids: Mutex<Ids>,
+}
+
+/// Indices handed out to ngrams longer than a char.
+#[derive(Debug, Clone, Default)]
+struct Ids {
+    keys: Vec<Key>,
+    index: HashMap<Key, usize>,
+}
+
+impl Ids {
+    fn index_of(&mut self, key: Key) -> usize {
+        if key[1] == NONE {
+            return key[0] as usize;
+        }
+        if let Some(&idx) = self.index.get(&key) {
+            return idx;
+        }
+        let idx = self.keys.len();
+        self.keys.push(key);
+        self.index.insert(key, idx);
+        idx
+    }
+}
+
+impl Clone for SparseCorpus {
+    fn clone(&self) -> Self {
+        Self {
+            char_list: self.char_list.clone(),
+            char_map: self.char_map.clone(),
+            tables: self.tables.clone(),
+            ids: Mutex::new(self.ids().clone()),
+        }
+    }
+}
+
+impl SparseCorpus {
+    /// An empty corpus counting `char_list`'s groups of chars.
+    pub fn with_char_list(char_list: &[Vec<char>]) -> Self {
+        let groups = std::iter::once(vec!['\0']).chain(char_list.iter().cloned());
+        Self::from_groups(groups.collect())
+    }
+
+    /// Panics if char ids would overflow a `u32`.
+    fn from_groups(char_list: Vec<Vec<char>>) -> Self {
+        assert!(
+            char_list.len() < NONE as usize,
+            "Too many chars for a SparseCorpus"
+        );
+
+        let char_map = char_list
+            .iter()
+            .enumerate()
+            .skip(1)
+            .flat_map(|(i, group)| group.iter().map(move |&c| (c, i as u32)))
+            .collect();
+        Self {
+            char_list,
+            char_map,
+            tables: Default::default(),
+            ids: Default::default(),
+        }
+    }
+
+    /// Counts every window of `text`, chars that aren't in the char list are
+    /// counted as invalid.
+    pub fn add_text(&mut self, text: &str) {
+        let text: Vec<char> = text.chars().collect();
+        for order in ORDERS {
+            for window in text.windows(order) {
+                let key = self.key(window);
+                self.add_key(Table::for_order(order), key, 1);
+            }
+        }
+        for window in text.windows(3) {
+            let key = self.key(&[window[0], window[2]]);
+            self.add_key(Table::Skipgrams, key, 1);
+        }
+    }
+
+    /// How many ngrams in `table` occur.
+    pub fn len(&self, table: Table) -> usize {
+        self.tables[table.slot()].len()
+    }
+
+    fn ids(&self) -> MutexGuard<'_, Ids> {
+        self.ids.lock().expect("Poisoned!")
+    }
+
+    fn key(&self, ngram: &[char]) -> Key {
+        let mut key = [NONE; 5];
+        for (id, c) in key.iter_mut().zip(ngram) {
+            *id = self.char_map.get(c).copied().unwrap_or_default();
+        }
+        key
+    }
+
+    /// The index of `key`, numbering it if it's new.
+    fn index_of(&self, key: Key) -> usize {
+        if key[1] == NONE {
+            return key[0] as usize;
+        }
+        self.ids().index_of(key)
+    }
+
+    fn key_at(&self, table: Table, idx: usize) -> Key {
+        if table == Table::Chars {
+            let mut key = [NONE; 5];
+            key[0] = idx as u32;
+            key
+        } else {
+            self.ids().keys[idx]
+        }
+    }
+
+    fn index(&self, ngram: &[char]) -> usize {
+        self.index_of(self.key(ngram))
+    }
+
+    fn unkey(&self, key: Key) -> Vec<char> {
+        key.iter()
+            .take_while(|&&id| id != NONE)
+            .map(|&id| self.char_list[id as usize][0])
+            .collect()
+    }
+
+    fn unindex(&self, order: usize, idx: usize) -> Vec<char> {
+        self.unkey(self.key_at(Table::for_order(order), idx))
+    }
+
+    /// Counts that drop to zero are removed.
+    fn add_key(&mut self, table: Table, key: Key, delta: i64) {
+        let counts = &mut self.tables[table.slot()];
+        let count = counts
+            .get(&key)
+            .copied()
+            .unwrap_or_default()
+            .saturating_add_delta(delta);
+        if count == 0 {
+            counts.remove(&key);
+        } else {
+            counts.insert(key, count);
+        }
+    }
+}
+
+impl From<&Corpus> for SparseCorpus {
+    fn from(corpus: &Corpus) -> Self {
+        let mut sparse = Self::from_groups(corpus.char_list.clone());
+        for table in Table::ALL {
+            for (i, &count) in DenseCorpusExt::table(corpus, table).iter().enumerate() {
+                if count == 0 {
+                    continue;
+                }
+                let ngram = corpus.uncorpus_ngram(table.order(), i);
+                let key = sparse.key(&ngram);
+                sparse.add_key(table, key, i64::from(count));
+            }
+        }
+        sparse
+    }
+}
+
+impl TryFrom<&SparseCorpus> for Corpus {
+    type Error = NarrowingError;
+
+    /// Refuses to narrow if any count would be truncated, the error's index
+    /// is the sparse one.
+    fn try_from(sparse: &SparseCorpus) -> Result<Self, Self::Error> {
+        // `with_char_list` puts the placeholder for invalid chars back at index 0
+        let mut char_list = sparse.char_list[1..].to_vec();
+        let mut corpus = Corpus::with_char_list(&mut char_list);
+        for table in Table::ALL {
+            for (&key, &count) in &sparse.tables[table.slot()] {
+                let narrow = u32::try_from(count).map_err(|_| NarrowingError {
+                    table,
+                    index: sparse.index_of(key),
+                    count,
+                })?;
+                let j = corpus.corpus_ngram(&sparse.unkey(key));
+                corpus.get_table(table)[j] += narrow;
+            }
+        }
+        Ok(corpus)
+    }
+}
+
+impl CorpusExt for SparseCorpus {
+    type Count = u64;
+
+    fn corpus_char(&self, char: &[char; 1]) -> usize {
+        self.index(char)
+    }
+    fn corpus_bigram(&self, bigram: &[char; 2]) -> usize {
+        self.index(bigram)
+    }
+    fn corpus_trigram(&self, trigram: &[char; 3]) -> usize {
+        self.index(trigram)
+    }
+    fn corpus_quadgram(&self, quadgram: &[char; 4]) -> usize {
+        self.index(quadgram)
+    }
+    fn corpus_pentagram(&self, pentagram: &[char; 5]) -> usize {
+        self.index(pentagram)
+    }
+    fn count_at(&self, table: Table, idx: usize) -> u64 {
+        self.tables[table.slot()]
+            .get(&self.key_at(table, idx))
+            .copied()
+            .unwrap_or_default()
+    }
+    /// Counts that drop to zero are removed.
+    fn add_at(&mut self, table: Table, idx: usize, delta: i64) {
+        let key = self.key_at(table, idx);
+        self.add_key(table, key, delta);
+    }
+    /// Numbered under one lock, which is released before they're read.
+    fn nonzero(&self, table: Table) -> impl Iterator<Item = usize> + '_ {
+        let mut ids = self.ids();
+        let indices: Vec<usize> = self.tables[table.slot()]
+            .keys()
+            .map(|&key| ids.index_of(key))
+            .collect();
+        indices.into_iter()
+    }
+    /// Read by key, so ngrams that don't occur aren't numbered.
+    fn count_ngram(&self, ngram: &[char]) -> u64 {
+        let table = Table::for_order(ngram.len());
+        self.tables[table.slot()]
+            .get(&self.key(ngram))
+            .copied()
+            .unwrap_or_default()
+    }
+    /// Keys are already chars' positions, padded with [`NONE`] rather than
+    /// zeroes, which sorts the same within a table.
+    fn ordered_counts(&self, table: Table) -> impl Iterator<Item = ([u32; 5], u64)> + '_ {
+        let mut counts: Vec<(Key, u64)> = self.tables[table.slot()]
+            .iter()
+            .map(|(&key, &count)| (key, count))
+            .collect();
+        counts.sort_unstable();
+        counts
+            .into_iter()
+            .map(|(key, count)| (key.map(|id| if id == NONE { 0 } else { id }), count))
+    }
+    fn char_list(&self) -> &[Vec<char>] {
+        &self.char_list
+    }
+    fn uncorpus_unigram(&self, idx: usize) -> char {
+        self.char_list[idx][0]
+    }
+    fn uncorpus_bigram(&self, idx: usize) -> Vec<char> {
+        self.unindex(2, idx)
+    }
+    fn uncorpus_trigram(&self, idx: usize) -> Vec<char> {
+        self.unindex(3, idx)
+    }
+    fn uncorpus_quadgram(&self, idx: usize) -> Vec<char> {
+        self.unindex(4, idx)
+    }
+    fn uncorpus_pentagram(&self, idx: usize) -> Vec<char> {
+        self.unindex(5, idx)
+    }
+
+    #[cfg(test)]
+    fn count_char(&self, c: char) -> u64 {
+        self.count_ngram(&[c])
+    }
+
+    #[cfg(test)]
+    fn count_bigram(&self, bg: [char; 2]) -> u64 {
+        self.count_ngram(&bg)
+    }
+
+    #[cfg(test)]
+    fn count_trigram(&self, tg: [char; 3]) -> u64 {
+        self.count_ngram(&tg)
+    }
+
+    #[cfg(test)]
+    fn count_skipgram(&self, sg: [char; 2]) -> u64 {
+        self.count_at(Table::Skipgrams, self.index(&sg))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adaptive_corpus::tests::verify_corpus_si_he;
+    use crate::provenance::corpus_sha256;
+    use crate::testing::{HE, MockCorpus, TEXT, sparse_of};
+    use crate::{Adapt, Rule};
+
+    use std::fs;
+    use test_log::test;
+
+    #[test]
+    fn sparse_he_matches_mock() {
+        let mut mock = MockCorpus::sample();
+        let mut sparse = sparse_of(TEXT);
+
+        mock.adapt(HE);
+        sparse.adapt(HE);
+
+        for table in Table::ALL {
+            let counts = mock.table(table);
+            for (i, &count) in counts.iter().enumerate() {
+                let ngram = mock.uncorpus_ngram(table.order(), i);
+                let j = sparse.corpus_ngram(&ngram);
+                assert_eq!(
+                    sparse.count_at(table, j),
+                    count,
+                    "{} {ngram:?}",
+                    table.name()
+                );
+            }
+            let present = counts.iter().filter(|&&c| c != 0).count();
+            assert_eq!(sparse.len(table), present, "{}", table.name());
+        }
+    }
+
+    #[test]
+    fn sparse_hashes_like_dense() {
+        // Looking ngrams up numbers them in another order, which isn't hashed
+        let mut sparse = sparse_of(TEXT);
+        sparse.corpus_ngram(&['t', 'h', 'e']);
+        sparse.corpus_ngram(&['a', 'n', 'd']);
+        let mut mock = MockCorpus::sample();
+        let before = corpus_sha256(&mock);
+        assert_eq!(corpus_sha256(&sparse), before);
+
+        mock.adapt(HE);
+        sparse.adapt(HE);
+        assert_eq!(corpus_sha256(&sparse), corpus_sha256(&mock));
+        assert_ne!(corpus_sha256(&mock), before);
+
+        let corpus = Corpus::try_from(&sparse).expect("counts don't fit in a u32");
+        assert_eq!(
+            corpus_sha256(&SparseCorpus::from(&corpus)),
+            corpus_sha256(&corpus)
+        );
+    }
+
+    #[test]
+    fn sparse_reads_dont_number_ngrams() {
+        let sparse = sparse_of(TEXT);
+        assert_eq!(sparse.count_ngram(&['t', 'h', 'e']), 5);
+        assert_eq!(sparse.count_ngram(&['t', 'h', '†']), 0);
+        assert!(sparse.ids().keys.is_empty());
+
+        // Visiting a table numbers the ngrams in it, and only those
+        let present = sparse.nonzero(Table::Trigrams).count();
+        assert_eq!(present, sparse.len(Table::Trigrams));
+        assert_eq!(sparse.ids().keys.len(), present);
+    }
+
+    #[test]
+    fn sparse_cjk_matches_rewritten_text() {
+        // Too many chars for pentagrams to be numbered in a `u64`
+        let mut groups: Vec<Vec<char>> = ('\u{4e00}'..'\u{9fa5}').map(|c| vec![c]).collect();
+        groups.push(vec!['†']);
+        let text = "天下的人的心天下的人天下心人的下";
+        let sparse = |text: &str| {
+            let mut sparse = SparseCorpus::with_char_list(&groups);
+            sparse.add_text(text);
+            sparse
+        };
+        let mut corpus = sparse(text);
+        let expected = sparse(&text.replace("的人", "的†"));
+
+        corpus.adapt(Rule::new(['的', '人'], ['的', '†']));
+
+        assert_eq!(corpus.count_char('†'), 2);
+        for table in [
+            Table::Chars,
+            Table::Bigrams,
+            Table::Skipgrams,
+            Table::Trigrams,
+        ] {
+            assert_eq!(corpus.len(table), expected.len(table), "{}", table.name());
+            for i in expected.nonzero(table) {
+                let ngram = expected.uncorpus_ngram(table.order(), i);
+                let j = corpus.corpus_ngram(&ngram);
+                assert_eq!(
+                    corpus.count_at(table, j),
+                    expected.count_at(table, i),
+                    "{} {ngram:?}",
+                    table.name()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn si_sparse_he() {
+        let b = fs::read("./corpora/shai-iweb.corpus").expect("couldn't read corpus file");
+        let corpus: Corpus = rmp_serde::from_slice(&b).expect("couldn't deserialize corpus");
+        let mut sparse = SparseCorpus::from(&corpus);
+        assert_eq!(sparse.count_bigram(['h', 'e']), 8729312);
+
+        sparse.adapt(HE);
+        assert_eq!(sparse.count_bigram(['h', '†']), 8729312);
+        verify_corpus_si_he(Corpus::try_from(&sparse).expect("counts don't fit in a u32"));
+    }
+}
@@ -0,0 +1,656 @@
+// Copyright 2025 antlers <antlers@illucid.net>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Corpora stored one table per file, adapted and compared an order at a time.
+//!
+//! A deserialized [`Corpus`] holds every table at once, and the pentagram
+//! table alone is most of that. Loading a base corpus and a reference corpus
+//! side by side (as the comparison tests used to) can OOM.
+//!
+//! A [`SplitCorpus`] is a directory holding a "skeleton" corpus (the char list
+//! with every table emptied, as msgpack) plus one file of little-endian `u32`
+//! counts per [`Table`]. [`SplitCorpus::split_file`] streams the tables out of
+//! a `.corpus` file without deserializing it, and adaptation memory-maps only
+//! the tables read and written by the order being adapted (see
+//! [`tables_read`]), changing them in place.
+//!
+//! Mapped tables live in the page cache rather than on the heap, so the kernel
+//! can drop their pages under pressure instead of OOMing. What's left on the
+//! heap is the skeleton plus the [`Deltas`](crate::Deltas) collected for one
+//! order, which is proportional to the ngrams the rule touches, not to the
+//! tables.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use km_adaptive_corpus::{Rule, SplitCorpus, Table};
+//!
+//! let mut corpus = SplitCorpus::split_file("./corpora/shai-iweb.corpus", "/tmp/si").unwrap();
+//! corpus.adapt(Rule::new(['h', 'e'], ['h', '†'])).unwrap();
+//!
+//! let reference = SplitCorpus::split_file("./corpora/shai-iweb-he.corpus", "/tmp/si-he").unwrap();
+//! assert!(corpus.diff(&reference, Table::Trigrams).unwrap().is_empty());
+//! ```
+
+use crate::adaptive_corpus::{Adapt, ORDERS, Rule, tables_read, tables_written};
+use crate::corpus_ext::dense_positions;
+use crate::count::Count;
+use crate::{CorpusExt, DenseCorpusExt, Table};
+
+use kc::Corpus;
+use memmap2::{MmapMut, MmapOptions};
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use tracing::instrument;
+
+const SKELETON: &str = "skeleton.msgpack";
+
+/// A corpus whose tables live in separate files under `dir`.
+#[derive(Debug, Clone)]
+pub struct SplitCorpus {
+    dir: PathBuf,
+}
+
+/// A count that differs between two split corpora.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    pub ngram: Vec<char>,
+    pub count: u32,
+    pub expected: u32,
+}
+
+impl SplitCorpus {
+    /// Writes `corpus` out under `dir`, dropping each table once it's written.
+    #[instrument(level = "debug", skip(corpus))]
+    pub fn split(mut corpus: Corpus, dir: impl AsRef<Path> + std::fmt::Debug) -> io::Result<Self> {
+        let split = Self {
+            dir: dir.as_ref().to_path_buf(),
+        };
+        fs::create_dir_all(&split.dir)?;
+
+        for table in Table::ALL {
+            let counts = std::mem::take(corpus.get_table(table));
+            split.store_table(table, &counts)?;
+        }
+
+        let b = rmp_serde::to_vec(&corpus).map_err(io::Error::other)?;
+        fs::write(split.dir.join(SKELETON), b)?;
+
+        Ok(split)
+    }
+
+    /// Splits a `.corpus` file, streaming each table straight to its own file.
+    ///
+    /// Only the skeleton is ever held in memory, the counts pass through a
+    /// buffer. The result can be re-opened with [`SplitCorpus::open`].
+    #[instrument(level = "debug", skip(path))]
+    pub fn split_file(
+        path: impl AsRef<Path>,
+        dir: impl AsRef<Path> + std::fmt::Debug,
+    ) -> io::Result<Self> {
+        let split = Self {
+            dir: dir.as_ref().to_path_buf(),
+        };
+        fs::create_dir_all(&split.dir)?;
+
+        let fields = table_fields()?;
+        let mut r = BufReader::new(fs::File::open(path)?);
+        let (head, nested) = read_head(&mut r)?;
+        let (len, named) = match nested {
+            Nested::Array(len) => (len, false),
+            Nested::Map(len) => (len, true),
+            _ => return Err(invalid("not a serialized Corpus".to_string())),
+        };
+
+        // Every other field is copied as is, tables are left empty
+        let mut skeleton = head;
+        for field in 0..len {
+            if named {
+                copy_value(&mut r, &mut skeleton)?;
+            }
+            match fields.get(&field) {
+                Some(&table) => {
+                    split.stream_table(&mut r, table)?;
+                    skeleton.push(0x90);
+                }
+                None => copy_value(&mut r, &mut skeleton)?,
+            }
+        }
+        fs::write(split.dir.join(SKELETON), skeleton)?;
+
+        Ok(split)
+    }
+
+    /// Opens a directory previously written by [`SplitCorpus::split`].
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        if !dir.join(SKELETON).is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no split corpus in {}", dir.display()),
+            ));
+        }
+        Ok(Self { dir })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Copies every file to `dir`, eg. to keep the base corpus around before adapting.
+    pub fn copy_to(&self, dir: impl AsRef<Path>) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        fs::copy(self.dir.join(SKELETON), dir.join(SKELETON))?;
+        for table in Table::ALL {
+            fs::copy(self.table_path(table), dir.join(table_file(table)))?;
+        }
+        Ok(Self { dir })
+    }
+
+    /// The char list, with every table empty.
+    pub fn skeleton(&self) -> io::Result<Corpus> {
+        let b = fs::read(self.dir.join(SKELETON))?;
+        rmp_serde::from_slice(&b).map_err(io::Error::other)
+    }
+
+    pub fn load_table(&self, table: Table) -> io::Result<Vec<u32>> {
+        let b = fs::read(self.table_path(table))?;
+        Ok(b.chunks_exact(4).map(le_u32).collect())
+    }
+
+    pub fn store_table(&self, table: Table, counts: &[u32]) -> io::Result<()> {
+        let mut w = BufWriter::new(fs::File::create(self.table_path(table))?);
+        for &count in counts {
+            w.write_all(&count.to_le_bytes())?;
+        }
+        w.flush()
+    }
+
+    /// The skeleton with only `tables` filled in.
+    pub fn load(&self, tables: &[Table]) -> io::Result<Corpus> {
+        let mut corpus = self.skeleton()?;
+        for &table in tables {
+            *corpus.get_table(table) = self.load_table(table)?;
+        }
+        Ok(corpus)
+    }
+
+    /// Writes `tables` of `corpus` back to disk.
+    pub fn store(&self, corpus: &mut Corpus, tables: &[Table]) -> io::Result<()> {
+        for &table in tables {
+            self.store_table(table, corpus.get_table(table))?;
+        }
+        Ok(())
+    }
+
+    /// Loads every table, for when memory isn't a concern.
+    pub fn join(&self) -> io::Result<Corpus> {
+        self.load(&Table::ALL)
+    }
+
+    /// Applies a rule in place, one order at a time.
+    ///
+    /// Each order maps the tables returned by [`tables_read`] and
+    /// [`tables_written`] for it and nothing else, so the pentagram table is
+    /// only mapped by the orders that read it. The heap holds the skeleton
+    /// and that order's deltas.
+    #[instrument(level = "debug", skip(self))]
+    pub fn adapt(&mut self, rule: Rule) -> io::Result<()> {
+        for order in ORDERS {
+            let mut tables = tables_read(order, rule).to_vec();
+            for &table in tables_written(order) {
+                if !tables.contains(&table) {
+                    tables.push(table);
+                }
+            }
+            let mut corpus = self.map(&tables, true)?;
+            corpus.adapt_order(order, rule);
+            corpus.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Every count of `table` that differs from the same ngram in `other`.
+    ///
+    /// The two corpora may have different char lists; ngrams are matched by
+    /// their chars, not their indices, and an ngram one of them has no index
+    /// for counts as 0 there. Only `table` is mapped, from each.
+    pub fn diff(&self, other: &SplitCorpus, table: Table) -> io::Result<Vec<Mismatch>> {
+        let corpus = self.map(&[table], false)?;
+        let other = other.map(&[table], false)?;
+
+        let mut mismatches = Vec::new();
+        for i in 0..corpus.len(table) {
+            let ngram = corpus.uncorpus_ngram(table.order(), i);
+            let count = corpus.count_at(table, i);
+            let expected = if indexable(&other, &ngram) {
+                other.count_at(table, other.corpus_ngram(&ngram))
+            } else {
+                0
+            };
+            if count != expected {
+                mismatches.push(Mismatch {
+                    ngram,
+                    count,
+                    expected,
+                });
+            }
+        }
+
+        // Ngrams `self` has no index for weren't visited above
+        for j in other.nonzero(table) {
+            let ngram = other.uncorpus_ngram(table.order(), j);
+            if !indexable(&corpus, &ngram) {
+                mismatches.push(Mismatch {
+                    ngram,
+                    count: 0,
+                    expected: other.count_at(table, j),
+                });
+            }
+        }
+        Ok(mismatches)
+    }
+
+    /// The skeleton over `tables` mapped from their files, changes to them are
+    /// written back if `writable`.
+    fn map(&self, tables: &[Table], writable: bool) -> io::Result<Mapped> {
+        let mut mapped = Mapped {
+            index: self.skeleton()?,
+            tables: Default::default(),
+        };
+        for &table in tables {
+            let path = self.table_path(table);
+            let file = fs::File::options().read(true).write(writable).open(path)?;
+            // SAFETY: Split corpora are only changed through `SplitCorpus`,
+            // which never has a table mapped twice at once
+            let map = unsafe {
+                if writable {
+                    MmapMut::map_mut(&file)?
+                } else {
+                    MmapOptions::new().map_copy(&file)?
+                }
+            };
+            mapped.tables[table.slot()] = Some(map);
+        }
+        Ok(mapped)
+    }
+
+    /// Copies the counts of `table` from a msgpack array in `r` to its file.
+    fn stream_table(&self, r: &mut impl Read, table: Table) -> io::Result<()> {
+        let len = match read_head(r)?.1 {
+            Nested::Array(len) => len,
+            _ => return Err(invalid(format!("{} isn't an array", table.name()))),
+        };
+        let mut w = BufWriter::new(fs::File::create(self.table_path(table))?);
+        for _ in 0..len {
+            w.write_all(&read_count(r)?.to_le_bytes())?;
+        }
+        w.flush()
+    }
+
+    fn table_path(&self, table: Table) -> PathBuf {
+        self.dir.join(table_file(table))
+    }
+}
+
+/// Whether `corpus` has an index for every char of `ngram`, rather than
+/// reading them as its placeholder for invalid chars.
+fn indexable<U: CorpusExt>(corpus: &U, ngram: &[char]) -> bool {
+    ngram
+        .iter()
+        .all(|&c| corpus.corpus_char(&[c]) != 0 || corpus.char_list()[0].contains(&c))
+}
+
+fn table_file(table: Table) -> String {
+    format!("{}.u32", table.name())
+}
+
+fn le_u32(b: &[u8]) -> u32 {
+    u32::from_le_bytes(b.try_into().expect("Four bytes!"))
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// A skeleton's char list over memory-mapped tables, see [`SplitCorpus::adapt`].
+///
+/// Reading a table that isn't mapped panics, rather than quietly reading
+/// zeroes.
+struct Mapped {
+    index: Corpus,
+    tables: [Option<MmapMut>; 6],
+}
+
+impl Mapped {
+    fn counts(&self, table: Table) -> &[u8] {
+        self.tables[table.slot()]
+            .as_deref()
+            .unwrap_or_else(|| panic!("{} isn't mapped", table.name()))
+    }
+
+    fn len(&self, table: Table) -> usize {
+        self.counts(table).len() / 4
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        for map in self.tables.iter().flatten() {
+            map.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl CorpusExt for Mapped {
+    type Count = u32;
+
+    fn corpus_char(&self, char: &[char; 1]) -> usize {
+        self.index.corpus_char(char[0])
+    }
+    fn corpus_bigram(&self, bigram: &[char; 2]) -> usize {
+        self.index.corpus_bigram(bigram)
+    }
+    fn corpus_trigram(&self, trigram: &[char; 3]) -> usize {
+        self.index.corpus_trigram(trigram)
+    }
+    fn corpus_quadgram(&self, quadgram: &[char; 4]) -> usize {
+        self.index.corpus_quadgram(quadgram)
+    }
+    fn corpus_pentagram(&self, pentagram: &[char; 5]) -> usize {
+        self.index.corpus_pentagram(pentagram)
+    }
+    fn count_at(&self, table: Table, idx: usize) -> u32 {
+        le_u32(&self.counts(table)[4 * idx..4 * idx + 4])
+    }
+    fn add_at(&mut self, table: Table, idx: usize, delta: i64) {
+        let count = self.count_at(table, idx).saturating_add_delta(delta);
+        let counts = self.tables[table.slot()]
+            .as_deref_mut()
+            .unwrap_or_else(|| panic!("{} isn't mapped", table.name()));
+        counts[4 * idx..4 * idx + 4].copy_from_slice(&count.to_le_bytes());
+    }
+    fn nonzero(&self, table: Table) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len(table)).filter(move |&i| self.count_at(table, i) != 0)
+    }
+    fn ordered_counts(&self, table: Table) -> impl Iterator<Item = ([u32; 5], u64)> + '_ {
+        let n = self.num_chars();
+        self.nonzero(table).map(move |i| {
+            let count = u64::from(self.count_at(table, i));
+            (dense_positions(i, table.order(), n), count)
+        })
+    }
+    fn char_list(&self) -> &[Vec<char>] {
+        &self.index.char_list
+    }
+    fn uncorpus_unigram(&self, idx: usize) -> char {
+        self.index.uncorpus_unigram(idx)
+    }
+    fn uncorpus_bigram(&self, idx: usize) -> Vec<char> {
+        self.index.uncorpus_bigram(idx)
+    }
+    fn uncorpus_trigram(&self, idx: usize) -> Vec<char> {
+        self.index.uncorpus_trigram(idx)
+    }
+    fn uncorpus_quadgram(&self, idx: usize) -> Vec<char> {
+        self.index.uncorpus_quadgram(idx)
+    }
+    fn uncorpus_pentagram(&self, idx: usize) -> Vec<char> {
+        self.index.uncorpus_pentagram(idx)
+    }
+
+    #[cfg(test)]
+    fn count_char(&self, c: char) -> u32 {
+        self.count_ngram(&[c])
+    }
+
+    #[cfg(test)]
+    fn count_bigram(&self, bg: [char; 2]) -> u32 {
+        self.count_ngram(&bg)
+    }
+
+    #[cfg(test)]
+    fn count_trigram(&self, tg: [char; 3]) -> u32 {
+        self.count_ngram(&tg)
+    }
+
+    #[cfg(test)]
+    fn count_skipgram(&self, sg: [char; 2]) -> u32 {
+        self.count_at(Table::Skipgrams, self.corpus_bigram(&sg))
+    }
+}
+
+/// What follows a msgpack value's head.
+enum Nested {
+    None,
+    Array(u64),
+    Map(u64),
+    Bytes(u64),
+}
+
+/// Reads a msgpack value's tag plus any length or fixed-size payload.
+fn read_head(r: &mut impl Read) -> io::Result<(Vec<u8>, Nested)> {
+    enum Kind {
+        Scalar,
+        Array,
+        Map,
+        Bytes,
+        Ext,
+    }
+
+    let mut head = vec![0];
+    r.read_exact(&mut head)?;
+    let tag = head[0];
+    let (extra, kind) = match tag {
+        0x00..=0x7f | 0xc0 | 0xc2 | 0xc3 | 0xe0..=0xff => (0, Kind::Scalar),
+        0x80..=0x8f => return Ok((head, Nested::Map(u64::from(tag & 0x0f)))),
+        0x90..=0x9f => return Ok((head, Nested::Array(u64::from(tag & 0x0f)))),
+        0xa0..=0xbf => return Ok((head, Nested::Bytes(u64::from(tag & 0x1f)))),
+        0xc4 | 0xd9 => (1, Kind::Bytes),
+        0xc5 | 0xda => (2, Kind::Bytes),
+        0xc6 | 0xdb => (4, Kind::Bytes),
+        0xc7 => (1, Kind::Ext),
+        0xc8 => (2, Kind::Ext),
+        0xc9 => (4, Kind::Ext),
+        0xcc | 0xd0 => (1, Kind::Scalar),
+        0xcd | 0xd1 | 0xd4 => (2, Kind::Scalar),
+        0xd5 => (3, Kind::Scalar),
+        0xca | 0xce | 0xd2 => (4, Kind::Scalar),
+        0xd6 => (5, Kind::Scalar),
+        0xcb | 0xcf | 0xd3 => (8, Kind::Scalar),
+        0xd7 => (9, Kind::Scalar),
+        0xd8 => (17, Kind::Scalar),
+        0xdc => (2, Kind::Array),
+        0xdd => (4, Kind::Array),
+        0xde => (2, Kind::Map),
+        0xdf => (4, Kind::Map),
+        0xc1 => return Err(invalid("msgpack tag 0xc1 is never used".to_string())),
+    };
+    head.resize(1 + extra, 0);
+    r.read_exact(&mut head[1..])?;
+
+    let nested = match kind {
+        Kind::Scalar => Nested::None,
+        Kind::Array => Nested::Array(be(&head[1..])),
+        Kind::Map => Nested::Map(be(&head[1..])),
+        Kind::Bytes => Nested::Bytes(be(&head[1..])),
+        // Plus the type byte
+        Kind::Ext => Nested::Bytes(be(&head[1..]) + 1),
+    };
+    Ok((head, nested))
+}
+
+fn be(b: &[u8]) -> u64 {
+    b.iter().fold(0, |n, &b| (n << 8) | u64::from(b))
+}
+
+/// Copies one msgpack value from `r` to `w`, whatever's nested in it included.
+fn copy_value(r: &mut impl Read, w: &mut impl Write) -> io::Result<()> {
+    let (head, nested) = read_head(r)?;
+    w.write_all(&head)?;
+    match nested {
+        Nested::None => {}
+        Nested::Array(len) => {
+            for _ in 0..len {
+                copy_value(r, w)?;
+            }
+        }
+        Nested::Map(len) => {
+            for _ in 0..2 * len {
+                copy_value(r, w)?;
+            }
+        }
+        Nested::Bytes(len) => {
+            let copied = io::copy(&mut r.by_ref().take(len), w)?;
+            if copied != len {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_count(r: &mut impl Read) -> io::Result<u32> {
+    let (head, _) = read_head(r)?;
+    let count = match head[0] {
+        tag @ 0x00..=0x7f => u64::from(tag),
+        0xcc..=0xcf => be(&head[1..]),
+        tag => return Err(invalid(format!("count with msgpack tag {tag:#x}"))),
+    };
+    u32::try_from(count).map_err(|_| invalid(format!("count {count} doesn't fit in a u32")))
+}
+
+/// Which top-level field of a serialized `Corpus` holds each table.
+///
+/// Found by serializing a corpus whose tables each hold one distinct count,
+/// so it follows however `kc::Corpus` lays out its fields.
+fn table_fields() -> io::Result<HashMap<u64, Table>> {
+    const PROBE: u32 = 0xc0ff_ee00;
+
+    let mut probe = Corpus::with_char_list(&mut vec![vec!['a']]);
+    for (k, table) in Table::ALL.into_iter().enumerate() {
+        *probe.get_table(table) = vec![PROBE + k as u32];
+    }
+    let b = rmp_serde::to_vec(&probe).map_err(io::Error::other)?;
+
+    let r = &mut &b[..];
+    let (len, named) = match read_head(r)?.1 {
+        Nested::Array(len) => (len, false),
+        Nested::Map(len) => (len, true),
+        _ => return Err(invalid("Corpus doesn't serialize as a struct".to_string())),
+    };
+    let mut fields = HashMap::new();
+    for field in 0..len {
+        if named {
+            copy_value(r, &mut io::sink())?;
+        }
+        let mut value = Vec::new();
+        copy_value(r, &mut value)?;
+        // An array of one u32
+        if let [0x91, 0xce, ref count @ ..] = value[..] {
+            let k = be(count).wrapping_sub(u64::from(PROBE));
+            if let Some(&table) = Table::ALL.get(k as usize) {
+                fields.insert(field, table);
+            }
+        }
+    }
+    if fields.len() != Table::ALL.len() {
+        return Err(io::Error::other("couldn't find every table in a Corpus"));
+    }
+    Ok(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SparseCorpus;
+    use crate::testing::{ALPHABET, HE, TEXT, sparse_of};
+
+    use test_log::test;
+
+    #[test]
+    fn split_file_streams_tables() {
+        let mut base = Corpus::try_from(&sparse_of(TEXT)).expect("counts don't fit in a u32");
+        let mut corpus = base.clone();
+        // Counts wide enough for every msgpack int encoding
+        corpus.chars[1] = u32::MAX;
+        corpus.bigrams[1] = 70000;
+        corpus.trigrams[1] = 300;
+
+        let dir = std::env::temp_dir().join("km_adaptive_corpus/split_file_streams_tables");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).expect("couldn't create scratch dir");
+        let path = dir.join("mock.corpus");
+        let b = rmp_serde::to_vec(&corpus).expect("couldn't serialize corpus");
+        fs::write(&path, b).expect("couldn't write corpus file");
+
+        let split =
+            SplitCorpus::split_file(&path, dir.join("split")).expect("couldn't split corpus");
+        let joined = split.join().expect("couldn't join corpus");
+        assert_eq!(joined.char_list, corpus.char_list);
+        for table in Table::ALL {
+            assert_eq!(
+                DenseCorpusExt::table(&joined, table),
+                DenseCorpusExt::table(&corpus, table),
+                "{}",
+                table.name()
+            );
+        }
+
+        // Adapting the mapped tables in place is adapting the whole corpus
+        let mut split =
+            SplitCorpus::split(base.clone(), dir.join("adapted")).expect("couldn't split corpus");
+        split.adapt(HE).expect("couldn't adapt corpus");
+        base.adapt(HE);
+        let expected =
+            SplitCorpus::split(base, dir.join("expected")).expect("couldn't split corpus");
+        for table in Table::ALL {
+            let mismatches = split
+                .diff(&expected, table)
+                .expect("couldn't compare corpora");
+            assert!(mismatches.is_empty(), "{} differ", table.name());
+        }
+    }
+
+    #[test]
+    fn diff_walks_both_corpora() {
+        let dir = std::env::temp_dir().join("km_adaptive_corpus/diff_walks_both_corpora");
+        let _ = fs::remove_dir_all(&dir);
+
+        // `s` is only in the second corpus's char list
+        let text = "the hens";
+        let base = Corpus::try_from(&sparse_of(text)).expect("counts don't fit in a u32");
+        let mut groups: Vec<Vec<char>> = ALPHABET.chars().map(|c| vec![c]).collect();
+        groups.push(vec!['s']);
+        let mut wider = SparseCorpus::with_char_list(&groups);
+        wider.add_text(text);
+        let wider = Corpus::try_from(&wider).expect("counts don't fit in a u32");
+
+        let base = SplitCorpus::split(base, dir.join("base")).expect("couldn't split corpus");
+        let wider = SplitCorpus::split(wider, dir.join("wider")).expect("couldn't split corpus");
+        let s = Mismatch {
+            ngram: vec!['s'],
+            count: 0,
+            expected: 1,
+        };
+        let mismatches = base
+            .diff(&wider, Table::Chars)
+            .expect("couldn't compare corpora");
+        assert!(mismatches.contains(&s), "{mismatches:?}");
+
+        let s = Mismatch {
+            ngram: vec!['s'],
+            count: 1,
+            expected: 0,
+        };
+        let mismatches = wider
+            .diff(&base, Table::Chars)
+            .expect("couldn't compare corpora");
+        assert!(mismatches.contains(&s), "{mismatches:?}");
+    }
+}
@@ -0,0 +1,190 @@
+// Copyright 2025 antlers <antlers@illucid.net>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Test fixtures shared by the modules' tests.
+
+use std::fs;
+use std::iter;
+use std::path::PathBuf;
+
+use crate::adaptive_corpus::ORDERS;
+use crate::corpus_ext::{dense_add_at, dense_nonzero, dense_ordered_counts};
+use crate::{CorpusExt, DenseCorpusExt, Rule, SparseCorpus, Table};
+
+pub(crate) const HE: Rule = Rule::new(['h', 'e'], ['h', '†']);
+
+pub(crate) const DAGGER_R: Rule = Rule::new(['†', 'r'], ['†', '‡']);
+
+/// A short text with `he` in most places it can be.
+pub(crate) const TEXT: &str = "the hen ate the heather then hid here and there ";
+
+/// Every char of `TEXT`, and the placeholders `HE` and `DAGGER_R` write.
+pub(crate) const ALPHABET: &str = "adehinrt †‡";
+
+/// A fresh scratch directory for `test`.
+pub(crate) fn cache_dir(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir()
+        .join("km_adaptive_corpus")
+        .join(test)
+        .join("cache");
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+/// Counts `text` in a sparse corpus over `ALPHABET`.
+pub(crate) fn sparse_of(text: &str) -> SparseCorpus {
+    let groups: Vec<Vec<char>> = ALPHABET.chars().map(|c| vec![c]).collect();
+    let mut sparse = SparseCorpus::with_char_list(&groups);
+    sparse.add_text(text);
+    sparse
+}
+
+/// A corpus backend that isn't `kc::Corpus`, counting every window of a text.
+///
+/// Index 0 is for invalid chars, like `kc::Corpus`, and ngrams are indexed as
+/// base `chars.len()` numbers.
+pub(crate) struct MockCorpus {
+    pub(crate) chars: Vec<char>,
+    pub(crate) char_list: Vec<Vec<char>>,
+    pub(crate) tables: [Vec<u64>; 6],
+}
+
+impl MockCorpus {
+    pub(crate) fn new(alphabet: &str, text: &str) -> Self {
+        let chars: Vec<char> = iter::once('\0').chain(alphabet.chars()).collect();
+        let n = chars.len();
+        let mut mock = Self {
+            char_list: chars.iter().map(|&c| vec![c]).collect(),
+            chars,
+            tables: Table::ALL.map(|table| vec![0; n.pow(table.order() as u32)]),
+        };
+
+        let text: Vec<char> = text.chars().collect();
+        for order in ORDERS {
+            for window in text.windows(order) {
+                let idx = mock.corpus_ngram(window);
+                mock.get_table(Table::for_order(order))[idx] += 1;
+            }
+        }
+        for window in text.windows(3) {
+            let idx = mock.corpus_bigram(&[window[0], window[2]]);
+            mock.get_skipgrams()[idx] += 1;
+        }
+        mock
+    }
+
+    /// Counts `text` over `ALPHABET`.
+    pub(crate) fn of(text: &str) -> Self {
+        Self::new(ALPHABET, text)
+    }
+
+    /// Counts `TEXT`.
+    pub(crate) fn sample() -> Self {
+        Self::of(TEXT)
+    }
+
+    fn index(&self, ngram: &[char]) -> usize {
+        let n = self.chars.len();
+        ngram.iter().fold(0, |idx, c| {
+            idx * n + self.chars.iter().position(|x| x == c).unwrap_or(0)
+        })
+    }
+
+    fn unindex(&self, order: usize, mut idx: usize) -> Vec<char> {
+        let n = self.chars.len();
+        let mut ngram = vec!['\0'; order];
+        for c in ngram.iter_mut().rev() {
+            *c = self.chars[idx % n];
+            idx /= n;
+        }
+        ngram
+    }
+}
+
+impl CorpusExt for MockCorpus {
+    type Count = u64;
+
+    fn corpus_char(&self, char: &[char; 1]) -> usize {
+        self.index(char)
+    }
+    fn corpus_bigram(&self, bigram: &[char; 2]) -> usize {
+        self.index(bigram)
+    }
+    fn corpus_trigram(&self, trigram: &[char; 3]) -> usize {
+        self.index(trigram)
+    }
+    fn corpus_quadgram(&self, quadgram: &[char; 4]) -> usize {
+        self.index(quadgram)
+    }
+    fn corpus_pentagram(&self, pentagram: &[char; 5]) -> usize {
+        self.index(pentagram)
+    }
+    fn count_at(&self, table: Table, idx: usize) -> u64 {
+        self.table(table)[idx]
+    }
+    fn add_at(&mut self, table: Table, idx: usize, delta: i64) {
+        dense_add_at(self, table, idx, delta);
+    }
+    fn nonzero(&self, table: Table) -> impl Iterator<Item = usize> + '_ {
+        dense_nonzero(self.table(table))
+    }
+    fn ordered_counts(&self, table: Table) -> impl Iterator<Item = ([u32; 5], u64)> + '_ {
+        dense_ordered_counts(self, table)
+    }
+    fn char_list(&self) -> &[Vec<char>] {
+        &self.char_list
+    }
+    fn uncorpus_unigram(&self, idx: usize) -> char {
+        self.chars[idx]
+    }
+    fn uncorpus_bigram(&self, idx: usize) -> Vec<char> {
+        self.unindex(2, idx)
+    }
+    fn uncorpus_trigram(&self, idx: usize) -> Vec<char> {
+        self.unindex(3, idx)
+    }
+    fn uncorpus_quadgram(&self, idx: usize) -> Vec<char> {
+        self.unindex(4, idx)
+    }
+    fn uncorpus_pentagram(&self, idx: usize) -> Vec<char> {
+        self.unindex(5, idx)
+    }
+
+    fn count_char(&self, c: char) -> u64 {
+        self.count_ngram(&[c])
+    }
+    fn count_bigram(&self, bg: [char; 2]) -> u64 {
+        self.count_ngram(&bg)
+    }
+    fn count_trigram(&self, tg: [char; 3]) -> u64 {
+        self.count_ngram(&tg)
+    }
+    fn count_skipgram(&self, sg: [char; 2]) -> u64 {
+        self.table(Table::Skipgrams)[self.index(&sg)]
+    }
+}
+
+impl DenseCorpusExt for MockCorpus {
+    fn table(&self, table: Table) -> &[u64] {
+        &self.tables[table.slot()]
+    }
+    fn get_chars(&mut self) -> &mut Vec<u64> {
+        &mut self.tables[Table::Chars.slot()]
+    }
+    fn get_bigrams(&mut self) -> &mut Vec<u64> {
+        &mut self.tables[Table::Bigrams.slot()]
+    }
+    fn get_trigrams(&mut self) -> &mut Vec<u64> {
+        &mut self.tables[Table::Trigrams.slot()]
+    }
+    fn get_skipgrams(&mut self) -> &mut Vec<u64> {
+        &mut self.tables[Table::Skipgrams.slot()]
+    }
+    fn get_quadgrams(&mut self) -> &mut Vec<u64> {
+        &mut self.tables[Table::Quadgrams.slot()]
+    }
+    fn get_pentagrams(&mut self) -> &mut Vec<u64> {
+        &mut self.tables[Table::Pentagrams.slot()]
+    }
+}
@@ -0,0 +1,211 @@
+// Copyright 2025 antlers <antlers@illucid.net>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Keystroke units longer than one char, eg. a letter and its combining marks.
+//!
+//! Everything else is keyed on `char`, so each unit gets an ID from the
+//! supplementary private use area, and text is encoded into IDs before it's
+//! counted. Rules are written against the same IDs, then results decoded.
+//! Single chars are their own IDs.
+//!
+//! Context [`Class`](crate::Class)es see the IDs, not the units.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use km_adaptive_corpus::{Adapt, Segmentation, SparseCorpus, Units};
+//!
+//! let mut units = Units::new(Segmentation::Graphemes);
+//! let text = units.encode("cafe\u{301} he\u{301} ");
+//! let rule = units.rule(["h", "e\u{301}"], ["h", "†"]);
+//!
+//! let mut char_list = units.char_list(&text);
+//! char_list.push(vec!['†']);
+//! let mut corpus = SparseCorpus::with_char_list(&char_list);
+//! corpus.add_text(&text);
+//! corpus.adapt(rule);
+//! ```
+
+use crate::adaptive_corpus::Rule;
+
+use serde::{Deserialize, Serialize};
+use unicode_segmentation::UnicodeSegmentation;
+
+use std::collections::{BTreeSet, HashMap};
+
+/// First ID handed out, the start of Supplementary Private Use Area-A.
+const FIRST_ID: u32 = 0xF0000;
+/// Last ID handed out, the end of Supplementary Private Use Area-B.
+const LAST_ID: u32 = 0x10FFFD;
+
+/// How text is split before defined units are matched.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Segmentation {
+    /// Every char is a unit unless it's part of a defined one.
+    #[default]
+    Chars,
+    /// Every extended grapheme cluster is a unit, new ones are defined as
+    /// they're encoded.
+    Graphemes,
+}
+
+/// Unit IDs, by unit and back.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Units {
+    segmentation: Segmentation,
+    ids: HashMap<String, char>,
+    /// By ID, from `FIRST_ID`.
+    units: Vec<String>,
+    /// Of the longest defined unit, in segments.
+    longest: usize,
+}
+
+impl Units {
+    pub fn new(segmentation: Segmentation) -> Self {
+        Self {
+            segmentation,
+            ..Default::default()
+        }
+    }
+
+    /// Units matched longest first, eg. digraphs typed as one key.
+    pub fn with_units(segmentation: Segmentation, units: &[&str]) -> Self {
+        let mut new = Self::new(segmentation);
+        for unit in units {
+            new.define(unit);
+        }
+        new
+    }
+
+    /// The ID of `unit`, defining it if it's new.
+    ///
+    /// Panics if `unit` is empty or the private use areas run out.
+    pub fn define(&mut self, unit: &str) -> char {
+        if let Some(id) = self.id(unit) {
+            return id;
+        }
+        assert!(!unit.is_empty(), "Empty unit");
+
+        // Neither area has surrogates, so every ID in between is a char
+        let id = FIRST_ID + self.units.len() as u32;
+        assert!(id <= LAST_ID, "Out of unit IDs");
+        let id = char::from_u32(id).unwrap();
+        self.ids.insert(unit.to_string(), id);
+        self.units.push(unit.to_string());
+        self.longest = self.longest.max(self.segments(unit).len());
+        id
+    }
+
+    /// The ID of `unit`, if it's a single char or has been defined.
+    pub fn id(&self, unit: &str) -> Option<char> {
+        let mut chars = unit.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Some(c),
+            _ => self.ids.get(unit).copied(),
+        }
+    }
+
+    /// The unit `id` stands for.
+    pub fn unit(&self, id: char) -> Option<&str> {
+        let i = (id as u32).checked_sub(FIRST_ID)?;
+        self.units.get(i as usize).map(String::as_str)
+    }
+
+    /// `text` as IDs, matching defined units longest first.
+    ///
+    /// Grapheme clusters that aren't defined yet are defined as they're met.
+    pub fn encode(&mut self, text: &str) -> String {
+        let segments = self.segments(text);
+        let mut encoded = String::new();
+        let mut i = 0;
+        while i < segments.len() {
+            let longest = self.longest.min(segments.len() - i);
+            let matched = (2..=longest)
+                .rev()
+                .find_map(|len| Some((len, *self.ids.get(&segments[i..i + len].concat())?)));
+            match matched {
+                Some((len, id)) => {
+                    encoded.push(id);
+                    i += len;
+                }
+                None => {
+                    encoded.push(self.define(segments[i]));
+                    i += 1;
+                }
+            }
+        }
+        encoded
+    }
+
+    /// The inverse of [`Units::encode`], IDs that weren't handed out are kept.
+    pub fn decode(&self, text: &str) -> String {
+        text.chars()
+            .map(|c| self.unit(c).map_or_else(|| c.to_string(), str::to_string))
+            .collect()
+    }
+
+    /// A rule substituting units, defining any that are new.
+    pub fn rule(&mut self, old: [&str; 2], new: [&str; 2]) -> Rule {
+        Rule::new(old.map(|u| self.define(u)), new.map(|u| self.define(u)))
+    }
+
+    /// Char list with one group per ID in `encoded`, eg. for
+    /// [`SparseCorpus::with_char_list`](crate::SparseCorpus::with_char_list).
+    pub fn char_list(&self, encoded: &str) -> Vec<Vec<char>> {
+        let ids: BTreeSet<char> = encoded.chars().collect();
+        ids.into_iter().map(|id| vec![id]).collect()
+    }
+
+    fn segments<'a>(&self, text: &'a str) -> Vec<&'a str> {
+        match self.segmentation {
+            Segmentation::Chars => text
+                .char_indices()
+                .map(|(i, c)| &text[i..i + c.len_utf8()])
+                .collect(),
+            Segmentation::Graphemes => text.graphemes(true).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Adapt, CorpusExt, SparseCorpus};
+
+    use test_log::test;
+
+    #[test]
+    fn units_graphemes() {
+        let mut units = Units::new(Segmentation::Graphemes);
+        let text = "he\u{301} saw the cafe\u{301} and he\u{301}r ";
+        let encoded = units.encode(text);
+        let e_acute = units.id("e\u{301}").expect("defined while encoding");
+        assert_eq!(encoded.chars().count(), text.chars().count() - 3);
+        assert_eq!(units.decode(&encoded), text);
+
+        let rule = units.rule(["h", "e\u{301}"], ["h", "†"]);
+        let mut char_list = units.char_list(&encoded);
+        char_list.push(vec!['†']);
+        let mut corpus = SparseCorpus::with_char_list(&char_list);
+        corpus.add_text(&encoded);
+        assert_eq!(corpus.count_bigram(['h', e_acute]), 2);
+        assert_eq!(corpus.count_bigram(['h', 'e']), 1);
+
+        corpus.adapt(rule);
+        assert_eq!(corpus.count_bigram(['h', e_acute]), 0);
+        assert_eq!(corpus.count_bigram(['h', '†']), 2);
+        assert_eq!(corpus.count_bigram(['h', 'e']), 1);
+        assert_eq!(corpus.count_char('†'), 2);
+    }
+
+    #[test]
+    fn units_digraphs() {
+        let mut units = Units::with_units(Segmentation::Chars, &["ng", "ngg"]);
+        let text = "sing a song of singgers";
+        let encoded = units.encode(text);
+        assert_eq!(encoded.chars().count(), text.chars().count() - 4);
+        assert!(encoded.contains(units.id("ngg").unwrap()));
+        assert_eq!(units.decode(&encoded), text);
+    }
+}
@@ -0,0 +1,221 @@
+// Copyright 2025 antlers <antlers@illucid.net>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! A corpus with `u64` counts, for corpora too large for Keycat's `u32`s.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::fs;
+//! use kc::Corpus;
+//! use km_adaptive_corpus::{Adapt, Rule, WideCorpus};
+//!
+//! let b = fs::read("./corpora/shai-iweb.corpus").unwrap();
+//! let corpus: Corpus = rmp_serde::from_slice(&b).unwrap();
+//! let mut wide = WideCorpus::from(corpus);
+//! wide.adapt(Rule::new(['h', 'e'], ['h', '†']));
+//! let corpus = Corpus::try_from(wide).expect("counts don't fit in a u32");
+//! ```
+
+use crate::corpus_ext::{dense_add_at, dense_nonzero, dense_ordered_counts};
+use crate::{CorpusExt, DenseCorpusExt, Table};
+
+use kc::Corpus;
+use serde::{Deserialize, Serialize};
+
+use std::fmt;
+
+/// Keycat's `Corpus`, but with `u64` tables.
+///
+/// The wrapped `Corpus` is only used for its char list, its tables are empty.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WideCorpus {
+    index: Corpus,
+    pub chars: Vec<u64>,
+    pub bigrams: Vec<u64>,
+    pub skipgrams: Vec<u64>,
+    pub trigrams: Vec<u64>,
+    pub quadgrams: Vec<u64>,
+    pub pentagrams: Vec<u64>,
+}
+
+/// A count that would be truncated by narrowing a [`WideCorpus`] to a `Corpus`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NarrowingError {
+    pub table: Table,
+    pub index: usize,
+    pub count: u64,
+}
+
+impl fmt::Display for NarrowingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} count {} at index {} doesn't fit in a u32",
+            self.table.name(),
+            self.count,
+            self.index
+        )
+    }
+}
+
+impl std::error::Error for NarrowingError {}
+
+impl WideCorpus {
+    /// Returns the first count that doesn't fit in a `u32`, if any.
+    pub fn check_narrow(&self) -> Result<(), NarrowingError> {
+        for table in Table::ALL {
+            let counts = DenseCorpusExt::table(self, table);
+            if let Some(index) = counts.iter().position(|&c| u32::try_from(c).is_err()) {
+                return Err(NarrowingError {
+                    table,
+                    index,
+                    count: counts[index],
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl From<Corpus> for WideCorpus {
+    fn from(mut corpus: Corpus) -> Self {
+        fn widen(counts: Vec<u32>) -> Vec<u64> {
+            counts.into_iter().map(u64::from).collect()
+        }
+
+        Self {
+            chars: widen(std::mem::take(&mut corpus.chars)),
+            bigrams: widen(std::mem::take(&mut corpus.bigrams)),
+            skipgrams: widen(std::mem::take(&mut corpus.skipgrams)),
+            trigrams: widen(std::mem::take(&mut corpus.trigrams)),
+            quadgrams: widen(std::mem::take(&mut corpus.quadgrams)),
+            pentagrams: widen(std::mem::take(&mut corpus.pentagrams)),
+            index: corpus,
+        }
+    }
+}
+
+impl TryFrom<WideCorpus> for Corpus {
+    type Error = NarrowingError;
+
+    /// Refuses to narrow if any count would be truncated.
+    fn try_from(wide: WideCorpus) -> Result<Self, Self::Error> {
+        wide.check_narrow()?;
+
+        fn narrow(counts: Vec<u64>) -> Vec<u32> {
+            counts.into_iter().map(|c| c as u32).collect()
+        }
+
+        let mut corpus = wide.index;
+        corpus.chars = narrow(wide.chars);
+        corpus.bigrams = narrow(wide.bigrams);
+        corpus.skipgrams = narrow(wide.skipgrams);
+        corpus.trigrams = narrow(wide.trigrams);
+        corpus.quadgrams = narrow(wide.quadgrams);
+        corpus.pentagrams = narrow(wide.pentagrams);
+        Ok(corpus)
+    }
+}
+
+impl CorpusExt for WideCorpus {
+    type Count = u64;
+
+    fn corpus_char(&self, char: &[char; 1]) -> usize {
+        self.index.corpus_char(char[0])
+    }
+    fn corpus_bigram(&self, bigram: &[char; 2]) -> usize {
+        self.index.corpus_bigram(bigram)
+    }
+    fn corpus_trigram(&self, trigram: &[char; 3]) -> usize {
+        self.index.corpus_trigram(trigram)
+    }
+    fn corpus_quadgram(&self, quadgram: &[char; 4]) -> usize {
+        self.index.corpus_quadgram(quadgram)
+    }
+    fn corpus_pentagram(&self, pentagram: &[char; 5]) -> usize {
+        self.index.corpus_pentagram(pentagram)
+    }
+    fn count_at(&self, table: Table, idx: usize) -> u64 {
+        self.table(table)[idx]
+    }
+    fn add_at(&mut self, table: Table, idx: usize, delta: i64) {
+        dense_add_at(self, table, idx, delta);
+    }
+    fn nonzero(&self, table: Table) -> impl Iterator<Item = usize> + '_ {
+        dense_nonzero(self.table(table))
+    }
+    fn ordered_counts(&self, table: Table) -> impl Iterator<Item = ([u32; 5], u64)> + '_ {
+        dense_ordered_counts(self, table)
+    }
+    fn char_list(&self) -> &[Vec<char>] {
+        &self.index.char_list
+    }
+    fn uncorpus_unigram(&self, idx: usize) -> char {
+        self.index.uncorpus_unigram(idx)
+    }
+    fn uncorpus_bigram(&self, idx: usize) -> Vec<char> {
+        self.index.uncorpus_bigram(idx)
+    }
+    fn uncorpus_trigram(&self, idx: usize) -> Vec<char> {
+        self.index.uncorpus_trigram(idx)
+    }
+    fn uncorpus_quadgram(&self, idx: usize) -> Vec<char> {
+        self.index.uncorpus_quadgram(idx)
+    }
+    fn uncorpus_pentagram(&self, idx: usize) -> Vec<char> {
+        self.index.uncorpus_pentagram(idx)
+    }
+
+    #[cfg(test)]
+    fn count_char(&self, c: char) -> u64 {
+        self.chars[self.index.corpus_char(c)]
+    }
+
+    #[cfg(test)]
+    fn count_bigram(&self, bg: [char; 2]) -> u64 {
+        self.bigrams[self.index.corpus_bigram(&bg)]
+    }
+
+    #[cfg(test)]
+    fn count_trigram(&self, tg: [char; 3]) -> u64 {
+        self.trigrams[self.index.corpus_trigram(&tg)]
+    }
+
+    #[cfg(test)]
+    fn count_skipgram(&self, sg: [char; 2]) -> u64 {
+        self.skipgrams[self.index.corpus_bigram(&sg)]
+    }
+}
+
+impl DenseCorpusExt for WideCorpus {
+    fn table(&self, table: Table) -> &[u64] {
+        match table {
+            Table::Chars => &self.chars,
+            Table::Bigrams => &self.bigrams,
+            Table::Skipgrams => &self.skipgrams,
+            Table::Trigrams => &self.trigrams,
+            Table::Quadgrams => &self.quadgrams,
+            Table::Pentagrams => &self.pentagrams,
+        }
+    }
+    fn get_chars(&mut self) -> &mut Vec<u64> {
+        &mut self.chars
+    }
+    fn get_bigrams(&mut self) -> &mut Vec<u64> {
+        &mut self.bigrams
+    }
+    fn get_trigrams(&mut self) -> &mut Vec<u64> {
+        &mut self.trigrams
+    }
+    fn get_skipgrams(&mut self) -> &mut Vec<u64> {
+        &mut self.skipgrams
+    }
+    fn get_quadgrams(&mut self) -> &mut Vec<u64> {
+        &mut self.quadgrams
+    }
+    fn get_pentagrams(&mut self) -> &mut Vec<u64> {
+        &mut self.pentagrams
+    }
+}
@@ -0,0 +1,171 @@
+// Copyright 2025 antlers <antlers@illucid.net>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Whole-word abbreviations, eg. a text expander that types "because" as "bc".
+//!
+//! A [`WordRule`] is a [`Rewrite`] that only matches between word boundaries
+//! (see [`Class::Boundary`]). Words are usually longer than the pentagrams
+//! can see, so the Markov estimate of how often a word occurs on its own can
+//! be well off. [`WordFreqs`] corrects it: each rule's changes are scaled so
+//! that its word makes up the same share of the corpus's words as it does of
+//! the frequency list. They're only ever scaled down, a rewrite can't take
+//! away more occurrences than the corpus has.
+//!
+//! Words are case-sensitive, like the corpus: "Because" is neither counted
+//! nor abbreviated by a rule for "because".
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::fs;
+//! use kc::Corpus;
+//! use km_adaptive_corpus::words::{WordFreqs, WordRule, abbreviate};
+//!
+//! let b = fs::read("./corpora/shai-iweb.corpus").unwrap();
+//! let corpus: Corpus = rmp_serde::from_slice(&b).unwrap();
+//! let freqs = WordFreqs::from_text(&fs::read_to_string("./shai-iweb.txt").unwrap());
+//! let corpus = abbreviate(corpus, &[WordRule::new("because", "bc")], &freqs);
+//! ```
+
+use crate::adaptive_corpus::context::{Class, Context};
+use crate::corpus_ext::add_chars;
+use crate::{CorpusExt, Rewrite};
+
+use kc::Corpus;
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::io::{self, BufRead};
+
+use tracing::{debug, instrument, warn};
+
+/// `word` is typed as `code`, when it's a whole word with the same case.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct WordRule {
+    pub word: String,
+    pub code: String,
+}
+
+impl WordRule {
+    pub fn new(word: &str, code: &str) -> Self {
+        Self {
+            word: word.to_string(),
+            code: code.to_string(),
+        }
+    }
+
+    pub fn rewrite(&self) -> Rewrite {
+        Rewrite::new(&self.word, &self.code)
+            .with_before(Context::Is(Class::Boundary))
+            .with_after(Context::Is(Class::Boundary))
+    }
+}
+
+/// How often each word occurs, as written.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct WordFreqs {
+    counts: HashMap<String, u64>,
+    total: u64,
+}
+
+impl WordFreqs {
+    /// Counts the words of `text`, split on [`Class::Boundary`] chars.
+    pub fn from_text(text: &str) -> Self {
+        let mut freqs = Self::default();
+        for word in text.split(|c| Class::Boundary.contains(c)) {
+            if !word.is_empty() {
+                freqs.add(word, 1);
+            }
+        }
+        freqs
+    }
+
+    /// Reads a word list of `word count` lines, eg. a frequency dictionary.
+    ///
+    /// Blank lines are skipped, anything else without a count is an error.
+    pub fn from_word_list(reader: impl BufRead) -> io::Result<Self> {
+        let mut freqs = Self::default();
+        for (n, line) in reader.lines().enumerate() {
+            let line = line?;
+            let mut fields = line.split_whitespace();
+            let Some(word) = fields.next() else {
+                continue;
+            };
+            let count = fields
+                .next()
+                .and_then(|count| count.parse().ok())
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("no count for {word:?} on line {}", n + 1),
+                    )
+                })?;
+            freqs.add(word, count);
+        }
+        Ok(freqs)
+    }
+
+    pub fn add(&mut self, word: &str, count: u64) {
+        *self.counts.entry(word.to_string()).or_default() += count;
+        self.total += count;
+    }
+
+    pub fn count(&self, word: &str) -> u64 {
+        self.counts.get(word).copied().unwrap_or(0)
+    }
+
+    /// `word`'s share of every word counted.
+    pub fn share(&self, word: &str) -> f64 {
+        if self.total == 0 {
+            0.0
+        } else {
+            self.count(word) as f64 / self.total as f64
+        }
+    }
+}
+
+/// Applies `rules` in order, after adding their codes to the char list.
+///
+/// Each rule sees the keystrokes of the ones before it, so a code shouldn't
+/// spell out a later rule's word.
+#[instrument(level = "debug", skip(corpus, freqs))]
+pub fn abbreviate(corpus: Corpus, rules: &[WordRule], freqs: &WordFreqs) -> Corpus {
+    let codes: Vec<char> = rules.iter().flat_map(|r| r.code.chars()).collect();
+    let mut corpus = add_chars(corpus, &codes);
+
+    for rule in rules {
+        let rewrite = rule.rewrite();
+        let estimate = rewrite.occurrences(&corpus);
+        let expected = freqs.share(&rule.word) * word_count(&corpus);
+        let scale = if estimate == 0.0 {
+            0.0
+        } else {
+            expected / estimate
+        };
+        debug!(word = rule.word, estimate, expected, scale);
+        if scale > 1.0 {
+            warn!(
+                word = rule.word,
+                scale, "word is more common than the corpus has it, clamping"
+            );
+        }
+        rewrite.apply_scaled(&mut corpus, scale.min(1.0));
+    }
+    corpus
+}
+
+/// Number of words in `corpus`, ie. word chars that follow a boundary.
+fn word_count<U: CorpusExt>(corpus: &U) -> f64 {
+    let chars: Vec<char> = (0..corpus.num_chars())
+        .map(|i| corpus.uncorpus_unigram(i))
+        .collect();
+
+    let mut count = 0u64;
+    for &a in chars.iter().filter(|&&c| Class::Boundary.contains(c)) {
+        for &b in chars.iter().filter(|&&c| Class::Word.contains(c)) {
+            count += corpus.count_ngram(&[a, b]).into();
+        }
+    }
+    count as f64
+}